@@ -1,7 +1,9 @@
 use derive_more::{Display, From};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
+    policy::Policy,
     tx::{
         incoming::{IncomingTx, IncomingTxDetails},
         stored::{TxDetails, TxState},
@@ -15,21 +17,58 @@ use crate::{
 #[serde(transparent)]
 pub struct AccountId(pub u16);
 
-#[cfg_attr(test, derive(Serialize))]
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum AccountState {
+    #[default]
     Active,
     Frozen,
 }
 
-impl Default for AccountState {
-    fn default() -> Self {
-        Self::Active
+#[derive(Error, Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TxRejection {
+    #[error("account is frozen")]
+    AccountFrozen,
+    #[error("account is not frozen")]
+    AccountNotFrozen,
+    #[error("insufficient funds")]
+    InsufficientFunds,
+    #[error("duplicate transaction id")]
+    DuplicateTx,
+    #[error("referenced transaction not found")]
+    UnknownTx,
+    #[error("referenced transaction is in `{0}` state")]
+    WrongTxState(TxState),
+    #[error("referenced transaction belongs to another client")]
+    AccountMismatch,
+    #[error("referenced transaction can't be disputed")]
+    NotDisputable,
+    #[error("referenced transaction can't be disputed again")]
+    DisputeLimitReached,
+    #[error("dispute window of the referenced transaction is closed")]
+    DisputeWindowClosed,
+}
+
+impl TxRejection {
+    /// Short name for labels and the like
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxRejection::AccountFrozen => "account-frozen",
+            TxRejection::AccountNotFrozen => "account-not-frozen",
+            TxRejection::InsufficientFunds => "insufficient-funds",
+            TxRejection::DuplicateTx => "duplicate-tx",
+            TxRejection::UnknownTx => "unknown-tx",
+            TxRejection::WrongTxState(_) => "wrong-tx-state",
+            TxRejection::AccountMismatch => "account-mismatch",
+            TxRejection::NotDisputable => "not-disputable",
+            TxRejection::DisputeLimitReached => "dispute-limit-reached",
+            TxRejection::DisputeWindowClosed => "dispute-window-closed",
+        }
     }
 }
 
-#[cfg_attr(test, derive(Serialize))]
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Account {
     pub balance: Money,
     pub held: Money,
@@ -37,77 +76,420 @@ pub struct Account {
 }
 
 impl Account {
-    pub fn apply_tx(&mut self, prev_tx: Option<&TxDetails>, tx: &IncomingTx) -> Option<TxDetails> {
+    pub fn apply_tx(
+        &mut self,
+        prev_tx: Option<&TxDetails>,
+        tx: &IncomingTx,
+        policy: &Policy,
+        now: u64,
+    ) -> Result<TxDetails, TxRejection> {
         match (prev_tx, &tx.details) {
             (None, IncomingTxDetails::Deposit(amount)) => {
-                if self.state == AccountState::Frozen {
-                    return None;
+                if self.state == AccountState::Frozen && !policy.frozen_deposits {
+                    return Err(TxRejection::AccountFrozen);
                 }
                 self.balance += amount;
-                Some(TxDetails {
+                Ok(TxDetails {
                     original_tx: *tx,
                     state: TxState::Complete,
+                    disputes: 0,
+                    applied_at: now,
                 })
             }
             (None, IncomingTxDetails::Withdrawal(amount)) => {
-                if self.state == AccountState::Frozen {
-                    return None;
+                if self.state == AccountState::Frozen && !policy.frozen_withdrawals {
+                    return Err(TxRejection::AccountFrozen);
                 }
                 if &self.balance < amount {
-                    return None;
+                    return Err(TxRejection::InsufficientFunds);
                 }
                 self.balance -= amount;
-                Some(TxDetails {
+                Ok(TxDetails {
+                    original_tx: *tx,
+                    state: TxState::Complete,
+                    disputes: 0,
+                    applied_at: now,
+                })
+            }
+            (None, IncomingTxDetails::Lock) => {
+                if self.state == AccountState::Frozen {
+                    return Err(TxRejection::AccountFrozen);
+                }
+                self.state = AccountState::Frozen;
+                Ok(TxDetails {
                     original_tx: *tx,
                     state: TxState::Complete,
+                    disputes: 0,
+                    applied_at: now,
                 })
             }
+            (None, IncomingTxDetails::Unlock) => {
+                if self.state != AccountState::Frozen {
+                    return Err(TxRejection::AccountNotFrozen);
+                }
+                self.state = AccountState::Active;
+                Ok(TxDetails {
+                    original_tx: *tx,
+                    state: TxState::Complete,
+                    disputes: 0,
+                    applied_at: now,
+                })
+            }
+            (
+                Some(_),
+                IncomingTxDetails::Deposit(_)
+                | IncomingTxDetails::Withdrawal(_)
+                | IncomingTxDetails::Lock
+                | IncomingTxDetails::Unlock,
+            ) => Err(TxRejection::DuplicateTx),
+            (None, _) => Err(TxRejection::UnknownTx),
+            (Some(TxDetails { original_tx, .. }), _) if original_tx.account != tx.account => {
+                Err(TxRejection::AccountMismatch)
+            }
             (
                 Some(
                     prev_tx @ TxDetails {
                         original_tx,
-                        state: TxState::Complete,
+                        state: TxState::Complete | TxState::Resolved,
+                        disputes,
+                        applied_at,
                     },
                 ),
                 IncomingTxDetails::Dispute,
             ) => {
-                // assumption: even if the account is frozen, some other tx might be disputed
-                let balance_effect = original_tx.details.balance_effect().unwrap();
+                // lock and unlock don't move any money
+                let balance_effect = original_tx
+                    .details
+                    .balance_effect()
+                    .ok_or(TxRejection::NotDisputable)?;
+                if policy.dispute_window_closed(*applied_at, now) {
+                    return Err(TxRejection::DisputeWindowClosed);
+                }
+                if *disputes >= policy.max_dispute_cycles {
+                    return Err(TxRejection::DisputeLimitReached);
+                }
+                if self.state == AccountState::Frozen && !policy.frozen_disputes {
+                    return Err(TxRejection::AccountFrozen);
+                }
+                if matches!(original_tx.details, IncomingTxDetails::Withdrawal(_))
+                    && !policy.dispute_withdrawals
+                {
+                    return Err(TxRejection::NotDisputable);
+                }
+                if self.balance < balance_effect && !policy.dispute_overdraft {
+                    return Err(TxRejection::InsufficientFunds);
+                }
                 self.balance -= balance_effect;
                 self.held += balance_effect;
-                Some(prev_tx.with_state(TxState::UnderDispute))
+                Ok(TxDetails {
+                    disputes: disputes + 1,
+                    ..prev_tx.with_state(TxState::UnderDispute)
+                })
             }
             (
                 Some(
                     prev_tx @ TxDetails {
                         original_tx,
                         state: TxState::UnderDispute,
+                        ..
                     },
                 ),
                 IncomingTxDetails::Resolve,
             ) => {
-                // assumption: even if the account is frozen, some other tx might be disputed
                 let balance_effect = original_tx.details.balance_effect().unwrap();
                 self.balance += balance_effect;
                 self.held -= balance_effect;
-                Some(prev_tx.with_state(TxState::Resolved))
+                Ok(prev_tx.with_state(TxState::Resolved))
             }
             (
                 Some(
                     prev_tx @ TxDetails {
                         original_tx,
                         state: TxState::UnderDispute,
+                        ..
                     },
                 ),
                 IncomingTxDetails::Chargeback,
             ) => {
-                // assumption: even if the account is frozen, some other tx might be disputed
                 let balance_effect = original_tx.details.balance_effect().unwrap();
+                if self.balance + self.held < balance_effect && !policy.chargeback_overdraft {
+                    return Err(TxRejection::InsufficientFunds);
+                }
                 self.held -= balance_effect;
                 self.state = AccountState::Frozen;
-                Some(prev_tx.with_state(TxState::ChargedBack))
+                Ok(prev_tx.with_state(TxState::ChargedBack))
             }
-            _ => None,
+            (Some(TxDetails { state, .. }), _) => Err(TxRejection::WrongTxState(*state)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        policy::{DisputeWindow, Policy},
+        tx::{
+            incoming::IncomingTx,
+            stored::{TxDetails, TxState},
+        },
+    };
+
+    use super::{Account, AccountState, TxRejection};
+
+    fn deposited(account: &mut Account) -> TxDetails {
+        account
+            .apply_tx(
+                None,
+                &IncomingTx::deposit(1, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn withdrawal_over_balance_is_rejected_as_insufficient_funds() {
+        let mut account = Account::default();
+        deposited(&mut account);
+        assert_eq!(
+            account.apply_tx(
+                None,
+                &IncomingTx::withdrawal(2, 1, "1.5").unwrap(),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn deposit_to_frozen_account_is_rejected() {
+        let mut account = Account {
+            state: AccountState::Frozen,
+            ..Default::default()
+        };
+        assert_eq!(
+            account.apply_tx(
+                None,
+                &IncomingTx::deposit(1, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::AccountFrozen)
+        );
+    }
+
+    #[test]
+    fn deposit_with_known_id_is_rejected_as_duplicate() {
+        let mut account = Account::default();
+        let prev_tx = deposited(&mut account);
+        assert_eq!(
+            account.apply_tx(
+                Some(&prev_tx),
+                &IncomingTx::deposit(1, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::DuplicateTx)
+        );
+    }
+
+    #[test]
+    fn dispute_of_unknown_tx_is_rejected() {
+        let mut account = Account::default();
+        assert_eq!(
+            account.apply_tx(None, &IncomingTx::dispute(1, 1), &Policy::default(), 0),
+            Err(TxRejection::UnknownTx)
+        );
+    }
+
+    #[test]
+    fn dispute_from_another_client_is_rejected() {
+        let mut account = Account::default();
+        let prev_tx = deposited(&mut account);
+        assert_eq!(
+            account.apply_tx(
+                Some(&prev_tx),
+                &IncomingTx::dispute(1, 2),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::AccountMismatch)
+        );
+    }
+
+    #[test]
+    fn resolve_of_undisputed_tx_is_rejected_with_its_state() {
+        let mut account = Account::default();
+        let prev_tx = deposited(&mut account);
+        assert_eq!(
+            account.apply_tx(
+                Some(&prev_tx),
+                &IncomingTx::resolve(1, 1),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::WrongTxState(TxState::Complete))
+        );
+    }
+
+    #[test]
+    fn deposit_to_frozen_account_is_accepted_if_policy_allows() {
+        let mut account = Account {
+            state: AccountState::Frozen,
+            ..Default::default()
+        };
+        let policy = Policy {
+            frozen_deposits: true,
+            ..Default::default()
+        };
+        assert!(account
+            .apply_tx(None, &IncomingTx::deposit(1, 1, "1.0").unwrap(), &policy, 0)
+            .is_ok());
+    }
+
+    #[test]
+    fn dispute_of_withdrawal_is_rejected_if_policy_forbids() {
+        let mut account = Account::default();
+        deposited(&mut account);
+        let withdrawal = account
+            .apply_tx(
+                None,
+                &IncomingTx::withdrawal(2, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0,
+            )
+            .unwrap();
+        let policy = Policy {
+            dispute_withdrawals: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            account.apply_tx(Some(&withdrawal), &IncomingTx::dispute(2, 1), &policy, 0),
+            Err(TxRejection::NotDisputable)
+        );
+    }
+
+    #[test]
+    fn dispute_into_overdraft_is_rejected_if_policy_forbids() {
+        let mut account = Account::default();
+        let deposit = deposited(&mut account);
+        account
+            .apply_tx(
+                None,
+                &IncomingTx::withdrawal(2, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0,
+            )
+            .unwrap();
+        let policy = Policy {
+            dispute_overdraft: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            account.apply_tx(Some(&deposit), &IncomingTx::dispute(1, 1), &policy, 0),
+            Err(TxRejection::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn chargeback_into_overdraft_is_rejected_if_policy_forbids() {
+        let mut account = Account::default();
+        let deposit = deposited(&mut account);
+        account
+            .apply_tx(
+                None,
+                &IncomingTx::withdrawal(2, 1, "1.0").unwrap(),
+                &Policy::default(),
+                0,
+            )
+            .unwrap();
+        let policy = Policy {
+            chargeback_overdraft: false,
+            ..Default::default()
+        };
+        let disputed = account
+            .apply_tx(Some(&deposit), &IncomingTx::dispute(1, 1), &policy, 0)
+            .unwrap();
+        assert_eq!(
+            account.apply_tx(Some(&disputed), &IncomingTx::chargeback(1, 1), &policy, 0),
+            Err(TxRejection::InsufficientFunds)
+        );
+        assert_eq!((account.balance, account.held), ((-1).into(), 1.into()));
+    }
+
+    #[test]
+    fn resolved_tx_can_be_disputed_again_within_policy_limit() {
+        let mut account = Account::default();
+        let policy = Policy {
+            max_dispute_cycles: 2,
+            ..Default::default()
+        };
+        let mut tx = deposited(&mut account);
+        for _ in 0..2 {
+            tx = account
+                .apply_tx(Some(&tx), &IncomingTx::dispute(1, 1), &policy, 0)
+                .unwrap();
+            tx = account
+                .apply_tx(Some(&tx), &IncomingTx::resolve(1, 1), &policy, 0)
+                .unwrap();
+        }
+        assert_eq!(tx.disputes, 2);
+        assert_eq!(
+            account.apply_tx(Some(&tx), &IncomingTx::dispute(1, 1), &policy, 0),
+            Err(TxRejection::DisputeLimitReached)
+        );
+    }
+
+    #[test]
+    fn dispute_after_the_window_is_rejected() {
+        let mut account = Account::default();
+        let policy = Policy {
+            dispute_window: Some(DisputeWindow::Seconds(10)),
+            ..Default::default()
+        };
+        let deposit = account
+            .apply_tx(
+                None,
+                &IncomingTx::deposit(1, 1, "1.0").unwrap(),
+                &policy,
+                100,
+            )
+            .unwrap();
+        assert_eq!(
+            account
+                .clone()
+                .apply_tx(Some(&deposit), &IncomingTx::dispute(1, 1), &policy, 111),
+            Err(TxRejection::DisputeWindowClosed)
+        );
+        assert!(account
+            .apply_tx(Some(&deposit), &IncomingTx::dispute(1, 1), &policy, 110)
+            .is_ok());
+    }
+
+    #[test]
+    fn unlock_of_active_account_is_rejected() {
+        let mut account = Account::default();
+        assert_eq!(
+            account.apply_tx(None, &IncomingTx::unlock(1, 1), &Policy::default(), 0),
+            Err(TxRejection::AccountNotFrozen)
+        );
+    }
+
+    #[test]
+    fn dispute_of_lock_is_rejected() {
+        let mut account = Account::default();
+        let lock = account
+            .apply_tx(None, &IncomingTx::lock(1, 1), &Policy::default(), 0)
+            .unwrap();
+        assert_eq!(
+            account.apply_tx(
+                Some(&lock),
+                &IncomingTx::dispute(1, 1),
+                &Policy::default(),
+                0
+            ),
+            Err(TxRejection::NotDisputable)
+        );
+    }
+}
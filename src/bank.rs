@@ -1,54 +1,438 @@
 use std::{
+    borrow::Cow,
     collections::{BTreeMap, HashMap},
     fmt::Debug,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Instant,
 };
 
-use kv::{Bucket, Integer, Raw};
+use kv::{Batch, Bucket, Integer, Raw};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
 
 use crate::{
-    account::{Account, AccountId},
-    tx::{incoming::IncomingTx, stored::TxDetails, TxId},
+    account::{Account, AccountId, TxRejection},
+    cache::lru::HitStats,
+    metrics::Metrics,
+    policy::{DisputeWindow, Policy},
+    tx::{
+        id_set::TxIdSet,
+        incoming::{IncomingTx, IncomingTxDetails},
+        stored::{DecodeError, TxDetails, TxState},
+        TxId,
+    },
 };
 
-pub struct Bank {
-    tx_cache: Box<dyn TxCache>,
-    accounts: BTreeMap<AccountId, Account>,
+const ACCOUNTS_FILE: &str = "accounts.json";
+const CLOCK_FILE: &str = "clock.json";
+const STORED_IDS_FILE: &str = "stored-ids.json";
+/// Kind of the account store the state was saved with, see [`AccountStore::kind`]
+const ACCOUNT_STORE_FILE: &str = "account-store.json";
+/// Copies of the state from before the run, kept from [`begin_run`] until [`Bank::save`] finishes
+const SNAPSHOT_DIR: &str = "snapshot";
+/// What the snapshot restores, it's complete once this is written
+const SNAPSHOT_MANIFEST: &str = "manifest.json";
+
+#[derive(Error, Debug)]
+pub enum StateError {
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("error (de)serializing accounts: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("cache error: {0}")]
+    Cache(#[from] CacheError),
+    #[error("state was saved with the {saved} account store, it can't be resumed with the {current} one")]
+    AccountStoreMismatch {
+        saved: String,
+        current: &'static str,
+    },
+}
+
+#[derive(Error, Debug)]
+pub enum CacheError {
+    #[error("storage error: {0}")]
+    Storage(#[from] kv::Error),
+    #[error("corrupt Tx details for tx {0}: {1}")]
+    Corrupt(TxId, DecodeError),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("corrupt account {0}: {1}")]
+    CorruptAccount(AccountId, serde_json::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ApplyTxError {
+    #[error("transaction rejected: {0}")]
+    Rejected(#[from] TxRejection),
+    #[error("cache error: {0}")]
+    Cache(#[from] CacheError),
+}
+
+pub struct Bank<C: TxCache = InMemoryTxCache, A: AccountStore = InMemoryAccountStore> {
+    tx_cache: C,
+    accounts: A,
+    policy: Policy,
+    clock: u64,
+    /// Clock at the last sweep of transactions out of the dispute window
+    evicted_at: u64,
+    /// Ids of all transactions stored with a dispute window, so that the ones evicted since aren't taken for unknown
+    stored_ids: TxIdSet,
+    /// Counters of this run, the stores fill in the rest in [`Bank::metrics`]
+    metrics: Metrics,
+    started: Option<Instant>,
 }
 
 impl Default for Bank {
     fn default() -> Self {
-        Self {
-            tx_cache: Box::new(InMemoryTxCache::default()),
-            accounts: Default::default(),
-        }
+        Self::with_cache(InMemoryTxCache::default())
     }
 }
 
-impl Bank {
-    pub fn with_cache(tx_cache: Box<dyn TxCache>) -> Self {
+impl<C: TxCache> Bank<C> {
+    pub fn with_cache(tx_cache: C) -> Self {
+        Self::with_stores(tx_cache, InMemoryAccountStore::default())
+    }
+}
+
+impl<C: TxCache, A: AccountStore> Bank<C, A> {
+    pub fn with_stores(tx_cache: C, accounts: A) -> Self {
         Self {
             tx_cache,
-            accounts: Default::default(),
+            accounts,
+            policy: Default::default(),
+            clock: 0,
+            evicted_at: 0,
+            stored_ids: Default::default(),
+            metrics: Default::default(),
+            started: None,
+        }
+    }
+
+    pub fn with_policy(self, policy: Policy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// Restores the state saved by [`Bank::save`] to `dir`, both stores are expected to be reopened from the same place
+    ///
+    /// Stores on disk change in place during the run, [`begin_run`] has to be called before they're opened for a failed
+    /// run to be rolled back
+    pub fn open(tx_cache: C, accounts: A, dir: &Path) -> Result<Self, StateError> {
+        // Another store would start from no accounts at all
+        let saved: Option<String> = read_or_default(&dir.join(ACCOUNT_STORE_FILE))?;
+        if let Some(saved) = saved.filter(|saved| saved != accounts.kind()) {
+            return Err(StateError::AccountStoreMismatch {
+                saved,
+                current: accounts.kind(),
+            });
+        }
+        let clock = read_or_default(&dir.join(CLOCK_FILE))?;
+        let stored_ids = read_or_default(&dir.join(STORED_IDS_FILE))?;
+        Ok(Self {
+            clock,
+            stored_ids,
+            ..Self::with_stores(tx_cache, accounts)
+        })
+    }
+
+    /// Flushes both stores and saves the rest to `dir`, so that a later run can continue from here with [`Bank::open`]
+    pub fn save(&mut self, dir: &Path) -> Result<(), StateError> {
+        self.tx_cache.flush()?;
+
+        fs::create_dir_all(dir)?;
+        self.accounts.save(dir)?;
+        write_atomically(&dir.join(ACCOUNT_STORE_FILE), &self.accounts.kind())?;
+        write_atomically(&dir.join(CLOCK_FILE), &self.clock)?;
+        write_atomically(&dir.join(STORED_IDS_FILE), &self.stored_ids)?;
+        // Only once everything else is in place, the manifest first so that a half removed snapshot isn't restored
+        let snapshot = dir.join(SNAPSHOT_DIR);
+        remove_path(&snapshot.join(SNAPSHOT_MANIFEST))?;
+        remove_path(&snapshot)?;
+        Ok(())
+    }
+
+    /// Bank's clock: the number of transactions applied so far, or the latest timestamp seen with a dispute window in
+    /// seconds
+    pub fn now(&self) -> u64 {
+        self.clock
+    }
+
+    /// Moves the clock of a dispute window in seconds to `timestamp`, unless it's already past it
+    pub fn advance_clock(&mut self, timestamp: u64) {
+        if let Some(DisputeWindow::Seconds(_)) = self.policy.dispute_window {
+            self.clock = self.clock.max(timestamp);
+        }
+    }
+
+    /// Applies the transaction and returns the new state of the transaction it refers to
+    ///
+    /// On a cache error the account is left untouched, as if the transaction never came
+    pub fn apply_tx(&mut self, tx: IncomingTx) -> Result<TxDetails, ApplyTxError> {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let result = self.apply(tx);
+
+        let r#type = tx.details.name();
+        match &result {
+            Ok(_) => *self.metrics.applied.entry(r#type).or_default() += 1,
+            Err(ApplyTxError::Rejected(reason)) => {
+                *self
+                    .metrics
+                    .rejected
+                    .entry((r#type, reason.name()))
+                    .or_default() += 1
+            }
+            Err(ApplyTxError::Cache(_)) => {}
+        }
+        self.metrics.elapsed = started.elapsed();
+        result
+    }
+
+    fn apply(&mut self, tx: IncomingTx) -> Result<TxDetails, ApplyTxError> {
+        if !matches!(self.policy.dispute_window, Some(DisputeWindow::Seconds(_))) {
+            self.clock += 1;
+        }
+        self.evict_expired()?;
+
+        let (policy, now) = (&self.policy, self.clock);
+        let prev_tx = self.tx_cache.get_ref_by_id(tx.id)?;
+        let lookups = &mut self.metrics.cache_lookups;
+        match prev_tx {
+            Some(_) => lookups.hits += 1,
+            None => lookups.misses += 1,
+        }
+        // The same no matter when the cache gets to evict it, funds held by disputes are never forgotten
+        let forgotten = match &prev_tx {
+            Some(prev) => {
+                prev.state != TxState::UnderDispute && policy.forgets(prev.applied_at, now)
+            }
+            None => self.stored_ids.contains(tx.id),
+        };
+        let mut account = match self.accounts.get(tx.account)? {
+            Some(account) => account,
+            None => {
+                // Clients show up in the output even if none of their transactions go through
+                self.accounts.store(tx.account, Account::default())?;
+                Account::default()
+            }
+        };
+
+        if forgotten {
+            // All that's left of a forgotten transaction is that its id is taken
+            return Err(match tx.details {
+                IncomingTxDetails::Deposit(_)
+                | IncomingTxDetails::Withdrawal(_)
+                | IncomingTxDetails::Lock
+                | IncomingTxDetails::Unlock => TxRejection::DuplicateTx,
+                _ => TxRejection::DisputeWindowClosed,
+            }
+            .into());
+        }
+
+        let new_tx_state = account.apply_tx(prev_tx.as_deref(), &tx, &self.policy, self.clock)?;
+        self.tx_cache.store(new_tx_state)?;
+        if self.policy.dispute_window.is_some() {
+            self.stored_ids.insert(tx.id);
+        }
+        self.accounts.store(tx.account, account)?;
+        Ok(new_tx_state)
+    }
+
+    /// Sweeps forgotten transactions out of the cache at most once per window, so that the cost per transaction stays
+    /// low, see [`Policy::forgets`]
+    fn evict_expired(&mut self) -> Result<(), CacheError> {
+        if let Some(window) = self.policy.dispute_window {
+            if self.clock - self.evicted_at >= window.size().max(1) {
+                self.tx_cache
+                    .evict_before(self.clock.saturating_sub(window.size().saturating_mul(2)))?;
+                self.evicted_at = self.clock;
+            }
         }
+        Ok(())
     }
 
-    pub fn apply_tx(&mut self, tx: IncomingTx) {
-        let account = self.accounts.entry(tx.account).or_default();
-        let prev_tx = self.tx_cache.get_by_id(tx.id);
+    pub fn flush(&mut self) -> Result<(), CacheError> {
+        self.tx_cache.flush()?;
+        self.accounts.flush()
+    }
+
+    /// Counters of transactions applied by this bank so far along with what the stores report
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            cache_hits: self.tx_cache.hit_stats(),
+            cache_bytes_written: self.tx_cache.bytes_written(),
+            account_bytes_written: self.accounts.bytes_written(),
+            ..self.metrics.clone()
+        }
+    }
+
+    pub fn account(&self, id: AccountId) -> Result<Option<Account>, CacheError> {
+        self.accounts.get(id)
+    }
+
+    /// Every account in id order, streamed from the store
+    pub fn accounts(&mut self) -> Result<AccountsIter<'_>, CacheError> {
+        self.accounts.iter()
+    }
+}
+
+/// Whether [`Bank::save`] has saved state to `dir`, on-disk stores found without it are left over from elsewhere
+pub fn has_saved_state(dir: &Path) -> bool {
+    dir.join(CLOCK_FILE).exists()
+}
 
-        if let Some(new_tx_state) = account.apply_tx(prev_tx.as_ref(), &tx) {
-            self.tx_cache.store(new_tx_state);
+/// A path [`begin_run`] restores, `saved` is false if there was nothing there before the run
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    path: PathBuf,
+    saved: bool,
+}
+
+/// Makes the run about to resume the state in `dir` all or nothing: rolls back whatever an earlier run that didn't
+/// finish changed, then snapshots the state for this run until [`Bank::save`] saves the new one
+///
+/// `stores` are the paths of on-disk stores the run changes in place, they must not be open yet. The snapshot is a copy
+/// of them, so it takes as long as copying the state
+pub fn begin_run(dir: &Path, stores: &[PathBuf]) -> Result<(), StateError> {
+    let snapshot = dir.join(SNAPSHOT_DIR);
+    let manifest = snapshot.join(SNAPSHOT_MANIFEST);
+    if manifest.exists() {
+        let entries: Vec<SnapshotEntry> = read_or_default(&manifest)?;
+        for (i, entry) in entries.iter().enumerate() {
+            remove_path(&entry.path)?;
+            if entry.saved {
+                copy_path(&snapshot.join(i.to_string()), &entry.path)?;
+            }
         }
     }
+    // Without a manifest the snapshot was cut short before the run started, the state is as it was
+    remove_path(&snapshot)?;
 
-    pub fn into_accounts(self) -> BTreeMap<AccountId, Account> {
-        self.accounts
+    fs::create_dir_all(&snapshot)?;
+    let files = [
+        ACCOUNTS_FILE,
+        CLOCK_FILE,
+        STORED_IDS_FILE,
+        ACCOUNT_STORE_FILE,
+    ]
+    .map(|name| dir.join(name));
+    let mut entries = Vec::new();
+    for (i, path) in files.iter().chain(stores).enumerate() {
+        let saved = path.exists();
+        if saved {
+            copy_path(path, &snapshot.join(i.to_string()))?;
+        }
+        // Restored by a later run, which may be started from elsewhere
+        let path = std::path::absolute(path)?;
+        entries.push(SnapshotEntry { path, saved });
     }
+    write_atomically(&manifest, &entries)
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Copies a file or a whole directory, the copies are synced to disk before the snapshot counts as taken
+fn copy_path(from: &Path, to: &Path) -> std::io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_path(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn read_or_default<T: DeserializeOwned + Default>(path: &Path) -> Result<T, StateError> {
+    if path.exists() {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    } else {
+        Ok(T::default())
+    }
+}
+
+/// Replaces the previous contents atomically, a crash halfway through must not lose them
+fn write_atomically(path: &Path, value: &impl Serialize) -> Result<(), StateError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut out = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut out, value)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
 }
 
 pub trait TxCache {
-    fn get_by_id(&self, id: TxId) -> Option<TxDetails>;
-    fn store(&mut self, tx: TxDetails);
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError>;
+
+    /// Same as [`TxCache::get_by_id`], but caches that keep transactions in memory lend them instead of copying
+    fn get_ref_by_id(&self, id: TxId) -> Result<Option<Cow<'_, TxDetails>>, CacheError> {
+        Ok(self.get_by_id(id)?.map(Cow::Owned))
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError>;
+    /// Makes sure everything stored so far survives the process
+    fn flush(&mut self) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    /// Removes transactions applied before `applied_at`, except the ones under dispute, their held funds have yet to be
+    /// released
+    ///
+    /// Caches that can't reclaim the space may keep them, the bank treats them as unknown either way
+    fn evict_before(&mut self, _applied_at: u64) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    /// Bytes written to disk so far, `None` for caches that don't write to disk or can't tell
+    fn bytes_written(&self) -> Option<u64> {
+        None
+    }
+
+    /// Hits and misses of caches that sit in front of slower storage
+    fn hit_stats(&self) -> Option<HitStats> {
+        None
+    }
+}
+
+impl<C: TxCache + ?Sized> TxCache for Box<C> {
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        (**self).get_by_id(id)
+    }
+
+    fn get_ref_by_id(&self, id: TxId) -> Result<Option<Cow<'_, TxDetails>>, CacheError> {
+        (**self).get_ref_by_id(id)
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        (**self).store(tx)
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        (**self).flush()
+    }
+
+    fn evict_before(&mut self, applied_at: u64) -> Result<(), CacheError> {
+        (**self).evict_before(applied_at)
+    }
+
+    fn bytes_written(&self) -> Option<u64> {
+        (**self).bytes_written()
+    }
+
+    fn hit_stats(&self) -> Option<HitStats> {
+        (**self).hit_stats()
+    }
 }
 
 #[derive(Clone, Debug, Default)]
@@ -57,37 +441,429 @@ pub struct InMemoryTxCache {
 }
 
 impl TxCache for InMemoryTxCache {
-    fn get_by_id(&self, id: TxId) -> Option<TxDetails> {
-        self.tx_by_id.get(&id).map(ToOwned::to_owned)
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        Ok(self.tx_by_id.get(&id).map(ToOwned::to_owned))
+    }
+
+    fn get_ref_by_id(&self, id: TxId) -> Result<Option<Cow<'_, TxDetails>>, CacheError> {
+        Ok(self.tx_by_id.get(&id).map(Cow::Borrowed))
     }
 
-    fn store(&mut self, tx: TxDetails) {
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
         self.tx_by_id.insert(tx.original_tx.id, tx);
+        Ok(())
+    }
+
+    fn evict_before(&mut self, applied_at: u64) -> Result<(), CacheError> {
+        self.tx_by_id
+            .retain(|_, tx| tx.applied_at >= applied_at || tx.state == TxState::UnderDispute);
+        Ok(())
     }
 }
 
+pub const DEFAULT_BATCH_SIZE: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// Stores transactions in a kv bucket, writes are grouped into batches of `batch_size`
 pub struct OnDiskTxCache<'c> {
     bucket: Bucket<'c, Integer, Raw>,
+    batch_size: NonZeroUsize,
+    /// Stored but not written to the bucket yet
+    pending: HashMap<TxId, TxDetails>,
+    bytes_written: u64,
 }
 
 impl<'c> OnDiskTxCache<'c> {
     pub fn new(bucket: Bucket<'c, Integer, Raw>) -> Self {
-        Self { bucket }
+        Self {
+            bucket,
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: Default::default(),
+            bytes_written: 0,
+        }
+    }
+
+    /// A batch size of 1 writes every transaction right away
+    pub fn with_batch_size(self, batch_size: NonZeroUsize) -> Self {
+        Self { batch_size, ..self }
+    }
+
+    /// Whether nothing has been stored yet, neither in this run nor in an earlier one
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty() && self.bucket.is_empty()
+    }
+
+    fn write_pending(&mut self) -> Result<(), CacheError> {
+        // Pending writes are kept until the batch makes it to the bucket, a failed one can be retried
+        let mut batch = Batch::new();
+        let mut bytes = 0;
+        for (id, tx) in &self.pending {
+            let key = Integer::from(id.0);
+            let value = Raw::from(*tx);
+            bytes += (key.as_ref().len() + value.len()) as u64;
+            batch.set(&key, &value)?;
+        }
+        self.bucket.batch(batch)?;
+        self.pending.clear();
+        self.bytes_written += bytes;
+        Ok(())
     }
 }
 
 impl<'c> TxCache for OnDiskTxCache<'c> {
-    fn get_by_id(&self, id: TxId) -> Option<TxDetails> {
-        let cached = self
-            .bucket
-            .get(&Integer::from(id.0))
-            .expect("can't retrieve Tx details from the cache")?;
-        Some(cached.into())
-    }
-
-    fn store(&mut self, tx: TxDetails) {
-        self.bucket
-            .set(&Integer::from(tx.original_tx.id.0), &tx.into())
-            .expect("can't store Tx details in the cache");
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        if let Some(pending) = self.pending.get(&id) {
+            return Ok(Some(*pending));
+        }
+        match self.bucket.get(&Integer::from(id.0))? {
+            Some(cached) => Ok(Some(
+                cached.try_into().map_err(|e| CacheError::Corrupt(id, e))?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        self.pending.insert(tx.original_tx.id, tx);
+        if self.pending.len() >= self.batch_size.get() {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        self.write_pending()?;
+        self.bucket.flush()?;
+        Ok(())
+    }
+
+    /// Scans and decodes the whole bucket, the bank only sweeps once per dispute window
+    fn evict_before(&mut self, applied_at: u64) -> Result<(), CacheError> {
+        self.write_pending()?;
+        let mut batch = Batch::new();
+        for item in self.bucket.iter() {
+            let item = item?;
+            let key: Integer = item.key()?;
+            let tx = match TxDetails::decode(&item.value::<Raw>()?) {
+                Ok(tx) => tx,
+                Err(e) => return Err(CacheError::Corrupt(TxId(u64::from(key) as u32), e)),
+            };
+            if tx.applied_at < applied_at && tx.state != TxState::UnderDispute {
+                batch.remove(&key)?;
+            }
+        }
+        self.bucket.batch(batch)?;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> Option<u64> {
+        Some(self.bytes_written)
+    }
+}
+
+pub type AccountsIter<'a> = Box<dyn Iterator<Item = Result<(AccountId, Account), CacheError>> + 'a>;
+
+/// Where the bank keeps accounts, the counterpart of [`TxCache`]
+pub trait AccountStore {
+    /// Name of the kind of store, saved along with the state so that it's resumed with the same kind
+    fn kind(&self) -> &'static str;
+    fn get(&self, id: AccountId) -> Result<Option<Account>, CacheError>;
+    fn store(&mut self, id: AccountId, account: Account) -> Result<(), CacheError>;
+    /// Makes sure everything stored so far survives the process
+    fn flush(&mut self) -> Result<(), CacheError> {
+        Ok(())
+    }
+
+    /// Saves accounts to `dir` for [`Bank::open`], stores that live on disk already only need to flush
+    fn save(&mut self, _dir: &Path) -> Result<(), StateError> {
+        Ok(self.flush()?)
+    }
+
+    /// Every account in id order, including the ones stored but not flushed yet
+    fn iter(&mut self) -> Result<AccountsIter<'_>, CacheError>;
+
+    /// Bytes written to disk so far, `None` for stores that don't write to disk
+    fn bytes_written(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl<S: AccountStore + ?Sized> AccountStore for Box<S> {
+    fn kind(&self) -> &'static str {
+        (**self).kind()
+    }
+
+    fn get(&self, id: AccountId) -> Result<Option<Account>, CacheError> {
+        (**self).get(id)
+    }
+
+    fn store(&mut self, id: AccountId, account: Account) -> Result<(), CacheError> {
+        (**self).store(id, account)
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        (**self).flush()
+    }
+
+    fn save(&mut self, dir: &Path) -> Result<(), StateError> {
+        (**self).save(dir)
+    }
+
+    fn iter(&mut self) -> Result<AccountsIter<'_>, CacheError> {
+        (**self).iter()
+    }
+
+    fn bytes_written(&self) -> Option<u64> {
+        (**self).bytes_written()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryAccountStore {
+    accounts: BTreeMap<AccountId, Account>,
+}
+
+impl InMemoryAccountStore {
+    /// Restores accounts saved by [`AccountStore::save`] to `dir`, empty if there are none
+    pub fn open(dir: &Path) -> Result<Self, StateError> {
+        Ok(Self {
+            accounts: read_or_default(&dir.join(ACCOUNTS_FILE))?,
+        })
+    }
+}
+
+impl AccountStore for InMemoryAccountStore {
+    fn kind(&self) -> &'static str {
+        "memory"
+    }
+
+    fn get(&self, id: AccountId) -> Result<Option<Account>, CacheError> {
+        Ok(self.accounts.get(&id).cloned())
+    }
+
+    fn store(&mut self, id: AccountId, account: Account) -> Result<(), CacheError> {
+        self.accounts.insert(id, account);
+        Ok(())
+    }
+
+    fn save(&mut self, dir: &Path) -> Result<(), StateError> {
+        write_atomically(&dir.join(ACCOUNTS_FILE), &self.accounts)
+    }
+
+    fn iter(&mut self) -> Result<AccountsIter<'_>, CacheError> {
+        Ok(Box::new(
+            self.accounts
+                .iter()
+                .map(|(id, account)| Ok((*id, account.clone()))),
+        ))
+    }
+}
+
+/// Stores accounts as JSON in a kv bucket, writes are grouped into batches of `batch_size`
+pub struct OnDiskAccountStore<'c> {
+    bucket: Bucket<'c, Integer, Raw>,
+    batch_size: NonZeroUsize,
+    /// Stored but not written to the bucket yet
+    pending: BTreeMap<AccountId, Account>,
+    bytes_written: u64,
+}
+
+impl<'c> OnDiskAccountStore<'c> {
+    pub fn new(bucket: Bucket<'c, Integer, Raw>) -> Self {
+        Self {
+            bucket,
+            batch_size: DEFAULT_BATCH_SIZE,
+            pending: Default::default(),
+            bytes_written: 0,
+        }
+    }
+
+    /// A batch size of 1 writes every account right away
+    pub fn with_batch_size(self, batch_size: NonZeroUsize) -> Self {
+        Self { batch_size, ..self }
+    }
+
+    fn write_pending(&mut self) -> Result<(), CacheError> {
+        // Same as for the transactions, nothing is dropped before the batch is written
+        let mut batch = Batch::new();
+        let mut bytes = 0;
+        for (&id, account) in &self.pending {
+            let key = account_key(id);
+            let json =
+                serde_json::to_vec(account).map_err(|e| CacheError::CorruptAccount(id, e))?;
+            bytes += (key.as_ref().len() + json.len()) as u64;
+            batch.set(&key, &Raw::from(json))?;
+        }
+        self.bucket.batch(batch)?;
+        self.pending.clear();
+        self.bytes_written += bytes;
+        Ok(())
+    }
+}
+
+// Integer keys are big-endian, so the bucket iterates in id order
+fn account_key(id: AccountId) -> Integer {
+    Integer::from(u32::from(id.0))
+}
+
+fn decode_account(id: AccountId, raw: &Raw) -> Result<Account, CacheError> {
+    serde_json::from_slice(raw).map_err(|e| CacheError::CorruptAccount(id, e))
+}
+
+impl<'c> AccountStore for OnDiskAccountStore<'c> {
+    fn kind(&self) -> &'static str {
+        "disk"
+    }
+
+    fn get(&self, id: AccountId) -> Result<Option<Account>, CacheError> {
+        if let Some(pending) = self.pending.get(&id) {
+            return Ok(Some(pending.clone()));
+        }
+        match self.bucket.get(&account_key(id))? {
+            Some(raw) => Ok(Some(decode_account(id, &raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn store(&mut self, id: AccountId, account: Account) -> Result<(), CacheError> {
+        self.pending.insert(id, account);
+        if self.pending.len() >= self.batch_size.get() {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        self.write_pending()?;
+        self.bucket.flush()?;
+        Ok(())
+    }
+
+    fn iter(&mut self) -> Result<AccountsIter<'_>, CacheError> {
+        self.write_pending()?;
+        Ok(Box::new(self.bucket.iter().map(|item| {
+            let item = item?;
+            let id = AccountId(u64::from(item.key::<Integer>()?) as u16);
+            Ok((id, decode_account(id, &item.value::<Raw>()?)?))
+        })))
+    }
+
+    fn bytes_written(&self) -> Option<u64> {
+        Some(self.bytes_written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, num::NonZeroUsize};
+
+    use kv::{Config, Integer, Raw, Store};
+    use tempdir::TempDir;
+
+    use crate::{
+        account::{Account, AccountId},
+        tx::{
+            incoming::IncomingTx,
+            stored::{TxDetails, TxState},
+            TxId,
+        },
+        Money,
+    };
+
+    use super::{AccountStore, InMemoryTxCache, OnDiskAccountStore, OnDiskTxCache, TxCache};
+
+    fn deposit(id: u32) -> TxDetails {
+        TxDetails {
+            original_tx: IncomingTx::deposit(id, 1, "1.0").unwrap(),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: id.into(),
+        }
+    }
+
+    #[test]
+    fn on_disk_cache_reads_pending_writes_and_writes_full_batches() {
+        let temp_dir = TempDir::new("nesse-bank").unwrap();
+        let store = Store::new(Config::new(temp_dir.path())).unwrap();
+        let bucket = store.bucket::<Integer, Raw>(Some("tx")).unwrap();
+        let mut cache =
+            OnDiskTxCache::new(bucket.clone()).with_batch_size(NonZeroUsize::new(3).unwrap());
+
+        cache.store(deposit(1)).unwrap();
+        cache.store(deposit(2)).unwrap();
+        assert_eq!(bucket.len(), 0);
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), Some(deposit(2)));
+
+        cache.store(deposit(3)).unwrap();
+        assert_eq!(bucket.len(), 3);
+
+        cache.store(deposit(4)).unwrap();
+        cache.flush().unwrap();
+        assert_eq!(bucket.len(), 4);
+        assert_eq!(cache.get_by_id(TxId(4)).unwrap(), Some(deposit(4)));
+    }
+
+    #[test]
+    fn on_disk_cache_evicts_written_and_pending_transactions() {
+        let temp_dir = TempDir::new("nesse-bank").unwrap();
+        let store = Store::new(Config::new(temp_dir.path())).unwrap();
+        let bucket = store.bucket::<Integer, Raw>(Some("tx")).unwrap();
+        let mut cache = OnDiskTxCache::new(bucket).with_batch_size(NonZeroUsize::new(2).unwrap());
+
+        for id in 1..=3 {
+            cache.store(deposit(id)).unwrap();
+        }
+        cache.evict_before(3).unwrap();
+
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), None);
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), None);
+        assert_eq!(cache.get_by_id(TxId(3)).unwrap(), Some(deposit(3)));
+    }
+
+    #[test]
+    fn in_memory_cache_lends_transactions_and_the_rest_copy_them() {
+        let temp_dir = TempDir::new("nesse-bank").unwrap();
+        let store = Store::new(Config::new(temp_dir.path())).unwrap();
+        let mut on_disk = OnDiskTxCache::new(store.bucket::<Integer, Raw>(Some("tx")).unwrap());
+        let mut in_memory = InMemoryTxCache::default();
+        on_disk.store(deposit(1)).unwrap();
+        in_memory.store(deposit(1)).unwrap();
+
+        assert!(matches!(
+            in_memory.get_ref_by_id(TxId(1)).unwrap(),
+            Some(Cow::Borrowed(&tx)) if tx == deposit(1)
+        ));
+        assert!(matches!(
+            on_disk.get_ref_by_id(TxId(1)).unwrap(),
+            Some(Cow::Owned(tx)) if tx == deposit(1)
+        ));
+        assert_eq!(in_memory.get_ref_by_id(TxId(2)).unwrap(), None);
+    }
+
+    #[test]
+    fn on_disk_account_store_streams_accounts_in_id_order() {
+        let temp_dir = TempDir::new("nesse-bank").unwrap();
+        let store = Store::new(Config::new(temp_dir.path())).unwrap();
+        let bucket = store.bucket::<Integer, Raw>(Some("accounts")).unwrap();
+        let mut accounts =
+            OnDiskAccountStore::new(bucket).with_batch_size(NonZeroUsize::new(2).unwrap());
+
+        for id in [300, 2, 1000] {
+            let account = Account {
+                balance: Money::from(id),
+                ..Default::default()
+            };
+            accounts.store(AccountId(id), account).unwrap();
+        }
+
+        let ids: Vec<_> = accounts
+            .iter()
+            .unwrap()
+            .map(|account| account.unwrap().0)
+            .collect();
+        assert_eq!(ids, [AccountId(2), AccountId(300), AccountId(1000)]);
+        assert_eq!(
+            accounts.get(AccountId(300)).unwrap().unwrap().balance,
+            Money::from(300)
+        );
     }
 }
@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+
+use crate::{
+    account::AccountId,
+    bank::{CacheError, TxCache},
+    policy::Policy,
+    tx::{
+        incoming::{IncomingTx, IncomingTxDetails},
+        stored::{TxDetails, TxState},
+        TxId,
+    },
+    Money,
+};
+
+/// `kinds` value of ids that haven't been stored
+const EMPTY: u8 = 0;
+/// `amount_slots` value of transactions without a kept amount
+const NO_AMOUNT: u32 = u32::MAX;
+/// Ids below this are always kept in the arrays, 16MB worth of them
+const MIN_DENSE_IDS: usize = 1 << 20;
+
+/// Keeps transactions in memory as parallel arrays indexed by transaction id: 16 bytes per id up to the largest
+/// one stored plus 16 bytes per kept amount, so it suits inputs with dense ids
+///
+/// The arrays only grow up to twice the number of transactions stored, ids far beyond that go to a hash map instead
+///
+/// Transactions out of the dispute window aren't evicted, there would be no space to reclaim
+///
+/// With [`CompactTxCache::with_disputable_amounts_only`] amounts of transactions that can never be disputed aren't
+/// kept, such transactions are still remembered to reject duplicates, but read back with a zero amount. Deposits can
+/// always be disputed, so this only saves memory when withdrawals can't
+#[derive(Clone, Debug, Default)]
+pub struct CompactTxCache {
+    /// Type and state of the transaction packed by `pack_kind`, `EMPTY` if there's none
+    kinds: Vec<u8>,
+    accounts: Vec<u16>,
+    disputes: Vec<u8>,
+    applied_at: Vec<u64>,
+    /// Index into `amounts`
+    amount_slots: Vec<u32>,
+    amounts: Vec<Money>,
+    /// Transactions with ids too far past the arrays
+    sparse: HashMap<TxId, TxDetails>,
+    /// Number of transactions in the arrays and `sparse` together
+    len: usize,
+    disputable_amounts_only: Option<Policy>,
+}
+
+impl CompactTxCache {
+    /// Only keeps amounts of transactions that can be disputed under `policy`, all transactions are still stored;
+    /// the bank must run with the same policy
+    pub fn with_disputable_amounts_only(self, policy: Policy) -> Self {
+        Self {
+            disputable_amounts_only: Some(policy),
+            ..self
+        }
+    }
+}
+
+impl CompactTxCache {
+    /// Whether the arrays may grow to index `i` without taking more than twice the memory the transactions need
+    fn fits_dense(&self, i: usize) -> bool {
+        i < self.kinds.len() || i < MIN_DENSE_IDS.max(2 * (self.len + 1))
+    }
+}
+
+impl TxCache for CompactTxCache {
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        let i = id.0 as usize;
+        let kind = match self.kinds.get(i) {
+            Some(&kind) if kind != EMPTY => kind,
+            _ => return Ok(self.sparse.get(&id).copied()),
+        };
+        let amount = match self.amount_slots[i] {
+            NO_AMOUNT => Money::ZERO,
+            slot => self.amounts[slot as usize],
+        };
+        let (details, state) = unpack_kind(kind, amount);
+
+        Ok(Some(TxDetails {
+            original_tx: IncomingTx {
+                id,
+                account: AccountId(self.accounts[i]),
+                details,
+            },
+            state,
+            disputes: self.disputes[i],
+            applied_at: self.applied_at[i],
+        }))
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        let id = tx.original_tx.id;
+        let i = id.0 as usize;
+        let is_new = match self.kinds.get(i) {
+            Some(&kind) => kind == EMPTY,
+            None => true,
+        };
+        if is_new && (self.sparse.contains_key(&id) || !self.fits_dense(i)) {
+            if self.sparse.insert(id, tx).is_none() {
+                self.len += 1;
+            }
+            return Ok(());
+        }
+        if is_new {
+            self.len += 1;
+        }
+        if i >= self.kinds.len() {
+            self.kinds.resize(i + 1, EMPTY);
+            self.accounts.resize(i + 1, 0);
+            self.disputes.resize(i + 1, 0);
+            self.applied_at.resize(i + 1, 0);
+            self.amount_slots.resize(i + 1, NO_AMOUNT);
+        }
+
+        self.kinds[i] = pack_kind(&tx);
+        self.accounts[i] = tx.original_tx.account.0;
+        self.disputes[i] = tx.disputes;
+        self.applied_at[i] = tx.applied_at;
+
+        let details = &tx.original_tx.details;
+        let keep_amount = self
+            .disputable_amounts_only
+            .is_none_or(|policy| policy.is_disputable(details));
+        match (details.amount(), self.amount_slots[i]) {
+            (Some(amount), NO_AMOUNT) if keep_amount => {
+                self.amount_slots[i] = self.amounts.len() as u32;
+                self.amounts.push(amount);
+            }
+            (Some(amount), slot) if slot != NO_AMOUNT => self.amounts[slot as usize] = amount,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn pack_kind(tx: &TxDetails) -> u8 {
+    let r#type = match tx.original_tx.details {
+        IncomingTxDetails::Deposit(_) => 0,
+        IncomingTxDetails::Withdrawal(_) => 1,
+        IncomingTxDetails::Dispute => 2,
+        IncomingTxDetails::Resolve => 3,
+        IncomingTxDetails::Chargeback => 4,
+        IncomingTxDetails::Lock => 5,
+        IncomingTxDetails::Unlock => 6,
+    };
+    let state = match tx.state {
+        TxState::Complete => 0,
+        TxState::UnderDispute => 1,
+        TxState::Resolved => 2,
+        TxState::ChargedBack => 3,
+    };
+    // never `EMPTY`
+    ((r#type << 2) | state) + 1
+}
+
+fn unpack_kind(kind: u8, amount: Money) -> (IncomingTxDetails, TxState) {
+    let kind = kind - 1;
+    let details = match kind >> 2 {
+        0 => IncomingTxDetails::Deposit(amount),
+        1 => IncomingTxDetails::Withdrawal(amount),
+        2 => IncomingTxDetails::Dispute,
+        3 => IncomingTxDetails::Resolve,
+        4 => IncomingTxDetails::Chargeback,
+        5 => IncomingTxDetails::Lock,
+        6 => IncomingTxDetails::Unlock,
+        _ => unreachable!("kinds are only written by pack_kind"),
+    };
+    let state = match kind & 0b11 {
+        0 => TxState::Complete,
+        1 => TxState::UnderDispute,
+        2 => TxState::Resolved,
+        _ => TxState::ChargedBack,
+    };
+    (details, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bank::TxCache,
+        policy::Policy,
+        tx::{
+            incoming::IncomingTx,
+            stored::{TxDetails, TxState},
+            TxId,
+        },
+    };
+
+    use super::CompactTxCache;
+
+    fn tx(original_tx: IncomingTx, state: TxState) -> TxDetails {
+        TxDetails {
+            original_tx,
+            state,
+            disputes: 0,
+            applied_at: 0,
+        }
+    }
+
+    #[test]
+    fn stored_transactions_read_back() {
+        let mut cache = CompactTxCache::default();
+        let deposit = tx(
+            IncomingTx::deposit(3, 2, "3.0400").unwrap(),
+            TxState::Complete,
+        );
+        let withdrawal = tx(
+            IncomingTx::withdrawal(1, 7, "1.5").unwrap(),
+            TxState::ChargedBack,
+        );
+        let unlock = tx(IncomingTx::unlock(5, 7), TxState::Complete);
+
+        for tx in [deposit, withdrawal, unlock] {
+            cache.store(tx).unwrap();
+        }
+        let disputed = TxDetails {
+            disputes: 1,
+            ..deposit.with_state(TxState::UnderDispute)
+        };
+        cache.store(disputed).unwrap();
+
+        assert_eq!(cache.get_by_id(TxId(3)).unwrap(), Some(disputed));
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), Some(withdrawal));
+        assert_eq!(cache.get_by_id(TxId(5)).unwrap(), Some(unlock));
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), None);
+        assert_eq!(cache.get_by_id(TxId(6)).unwrap(), None);
+        assert_eq!(cache.amounts.len(), 2);
+    }
+
+    #[test]
+    fn ids_far_apart_are_kept_sparse() {
+        let mut cache = CompactTxCache::default();
+        let deposit = tx(IncomingTx::deposit(1, 2, "1.0").unwrap(), TxState::Complete);
+        let far = tx(
+            IncomingTx::deposit(4_000_000_000, 2, "2.0").unwrap(),
+            TxState::Complete,
+        );
+
+        cache.store(deposit).unwrap();
+        cache.store(far).unwrap();
+        cache.store(far.with_state(TxState::UnderDispute)).unwrap();
+
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), Some(deposit));
+        assert_eq!(
+            cache.get_by_id(TxId(4_000_000_000)).unwrap(),
+            Some(far.with_state(TxState::UnderDispute))
+        );
+        assert_eq!(cache.kinds.len(), 2);
+        assert_eq!(cache.len, 2);
+    }
+
+    #[test]
+    fn disputable_amounts_only_drops_amounts_of_the_rest() {
+        let policy = Policy {
+            dispute_withdrawals: false,
+            ..Default::default()
+        };
+        let mut cache = CompactTxCache::default().with_disputable_amounts_only(policy);
+        let deposit = tx(IncomingTx::deposit(1, 2, "3.0").unwrap(), TxState::Complete);
+
+        cache.store(deposit).unwrap();
+        cache
+            .store(tx(
+                IncomingTx::withdrawal(2, 2, "1.5").unwrap(),
+                TxState::Complete,
+            ))
+            .unwrap();
+
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), Some(deposit));
+        assert_eq!(
+            cache.get_by_id(TxId(2)).unwrap(),
+            Some(tx(
+                IncomingTx::withdrawal(2, 2, "0").unwrap(),
+                TxState::Complete
+            ))
+        );
+        assert_eq!(cache.amounts.len(), 1);
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    cell::{Cell, RefCell},
+    num::NonZeroUsize,
+};
+
+use lru::LruCache;
+
+use crate::{
+    bank::{CacheError, TxCache},
+    tx::{
+        stored::{TxDetails, TxState},
+        TxId,
+    },
+};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HitStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Keeps up to `capacity` recently used transactions in memory in front of another cache,
+/// writes go through to the inner cache right away
+pub struct LruTxCache<C: TxCache> {
+    inner: C,
+    // lookups take `&self`, but still have to bump the entry
+    hot: RefCell<LruCache<TxId, TxDetails>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<C: TxCache> LruTxCache<C> {
+    pub fn new(inner: C, capacity: NonZeroUsize) -> Self {
+        Self {
+            inner,
+            hot: RefCell::new(LruCache::new(capacity.get())),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: TxCache> TxCache for LruTxCache<C> {
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        if let Some(tx) = self.hot.borrow_mut().get(&id) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(Some(*tx));
+        }
+        self.misses.set(self.misses.get() + 1);
+
+        let tx = self.inner.get_by_id(id)?;
+        if let Some(tx) = tx {
+            self.hot.borrow_mut().put(id, tx);
+        }
+        Ok(tx)
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        self.inner.store(tx)?;
+        self.hot.get_mut().put(tx.original_tx.id, tx);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        self.inner.flush()
+    }
+
+    fn evict_before(&mut self, applied_at: u64) -> Result<(), CacheError> {
+        self.inner.evict_before(applied_at)?;
+        let hot = self.hot.get_mut();
+        let expired: Vec<TxId> = hot
+            .iter()
+            .filter(|(_, tx)| tx.applied_at < applied_at && tx.state != TxState::UnderDispute)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            hot.pop(&id);
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> Option<u64> {
+        self.inner.bytes_written()
+    }
+
+    fn hit_stats(&self) -> Option<HitStats> {
+        Some(HitStats {
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use crate::{
+        bank::{InMemoryTxCache, TxCache},
+        tx::{
+            incoming::IncomingTx,
+            stored::{TxDetails, TxState},
+            TxId,
+        },
+    };
+
+    use super::{HitStats, LruTxCache};
+
+    fn deposit(id: u32) -> TxDetails {
+        TxDetails {
+            original_tx: IncomingTx::deposit(id, 1, "1.0").unwrap(),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: 0,
+        }
+    }
+
+    #[test]
+    fn evicted_transactions_are_read_from_the_inner_cache() {
+        let mut cache = LruTxCache::new(InMemoryTxCache::default(), NonZeroUsize::new(2).unwrap());
+        for id in 1..=3 {
+            cache.store(deposit(id)).unwrap();
+        }
+
+        assert_eq!(cache.get_by_id(TxId(3)).unwrap(), Some(deposit(3)));
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), Some(deposit(1)));
+        // 1 has just pushed 2 out
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), Some(deposit(2)));
+        assert_eq!(cache.get_by_id(TxId(4)).unwrap(), None);
+
+        assert_eq!(cache.hit_stats(), Some(HitStats { hits: 1, misses: 3 }));
+    }
+}
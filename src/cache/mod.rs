@@ -0,0 +1,3 @@
+pub mod compact;
+pub mod lru;
+pub mod sqlite;
@@ -0,0 +1,298 @@
+use std::{
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, types::Type, Connection, OptionalExtension, Row};
+
+use crate::{
+    account::AccountId,
+    bank::{CacheError, TxCache, DEFAULT_BATCH_SIZE},
+    tx::{
+        incoming::{IncomingTx, IncomingTxDetails},
+        stored::{TxDetails, TxState},
+        TxId,
+    },
+    Money,
+};
+
+/// Stores transactions in an SQLite table that can be queried after the run:
+///
+/// ```sql
+/// SELECT account, SUM(amount) FROM tx WHERE type = 'deposit' AND state = 'charged-back' GROUP BY account;
+/// ```
+///
+/// Amounts are stored as text to keep them exact, writes are committed in batches of `batch_size`, a batch that isn't
+/// full yet is only committed by [`TxCache::flush`] and rolled back if the cache is dropped before that
+pub struct SqliteTxCache {
+    conn: Connection,
+    batch_size: NonZeroUsize,
+    uncommitted: usize,
+    /// Size of the values in the uncommitted rows
+    uncommitted_bytes: u64,
+    bytes_written: u64,
+}
+
+impl SqliteTxCache {
+    pub fn open(path: &Path) -> Result<Self, CacheError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// The database at `path` along with its rollback journal, which is only there while a write is under way
+    pub fn files(path: &Path) -> [PathBuf; 2] {
+        let mut journal = path.as_os_str().to_owned();
+        journal.push("-journal");
+        [path.to_owned(), journal.into()]
+    }
+
+    pub fn in_memory() -> Result<Self, CacheError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, CacheError> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS tx (
+                id INTEGER PRIMARY KEY,
+                account INTEGER NOT NULL,
+                type TEXT NOT NULL,
+                amount TEXT,
+                state TEXT NOT NULL,
+                disputes INTEGER NOT NULL,
+                applied_at INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS tx_applied_at ON tx (applied_at);",
+        )?;
+        Ok(Self {
+            conn,
+            batch_size: DEFAULT_BATCH_SIZE,
+            uncommitted: 0,
+            uncommitted_bytes: 0,
+            bytes_written: 0,
+        })
+    }
+
+    /// Whether there are no transactions in the database, including ones stored by earlier runs
+    pub fn is_empty(&self) -> Result<bool, CacheError> {
+        Ok(!self
+            .conn
+            .query_row("SELECT EXISTS (SELECT 1 FROM tx)", [], |row| row.get(0))?)
+    }
+
+    /// A batch size of 1 commits every transaction right away
+    pub fn with_batch_size(mut self, batch_size: NonZeroUsize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    fn commit(&mut self) -> Result<(), CacheError> {
+        if self.uncommitted > 0 {
+            self.conn.execute_batch("COMMIT")?;
+            self.uncommitted = 0;
+            self.bytes_written += self.uncommitted_bytes;
+            self.uncommitted_bytes = 0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SqliteTxCache {
+    fn drop(&mut self) {
+        if self.uncommitted > 0 {
+            // A failed rollback leaves the transaction open, sqlite rolls it back once the connection is closed
+            let _ = self.conn.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+impl TxCache for SqliteTxCache {
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, account, type, amount, state, disputes, applied_at FROM tx WHERE id = ?",
+        )?;
+        Ok(stmt.query_row([id.0], row_to_tx).optional()?)
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        if self.uncommitted == 0 {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        let original_tx = &tx.original_tx;
+        let r#type = original_tx.details.name();
+        let amount = original_tx
+            .details
+            .amount()
+            .map(|amount| amount.to_string());
+        let state = tx.state.to_string();
+        self.conn
+            .prepare_cached(
+                "INSERT OR REPLACE INTO tx (id, account, type, amount, state, disputes, applied_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?
+            .execute(params![
+                original_tx.id.0,
+                original_tx.account.0,
+                r#type,
+                amount,
+                state,
+                tx.disputes,
+                tx.applied_at,
+            ])?;
+        self.uncommitted += 1;
+        // The integer columns take up to 8 bytes each
+        self.uncommitted_bytes +=
+            (4 * 8 + r#type.len() + amount.map_or(0, |amount| amount.len()) + state.len()) as u64;
+        if self.uncommitted >= self.batch_size.get() {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), CacheError> {
+        self.commit()
+    }
+
+    /// Size of the values in the committed rows, without the pages, indexes and journal SQLite writes them to
+    fn bytes_written(&self) -> Option<u64> {
+        Some(self.bytes_written)
+    }
+
+    fn evict_before(&mut self, applied_at: u64) -> Result<(), CacheError> {
+        self.commit()?;
+        self.conn.execute(
+            "DELETE FROM tx WHERE applied_at < ? AND state != 'under-dispute'",
+            [applied_at],
+        )?;
+        Ok(())
+    }
+}
+
+fn row_to_tx(row: &Row) -> rusqlite::Result<TxDetails> {
+    let invalid = |column: usize, message: String| {
+        rusqlite::Error::FromSqlConversionFailure(column, Type::Text, message.into())
+    };
+
+    let amount = row
+        .get::<_, Option<String>>(3)?
+        .map(|amount| Money::from_str_exact(&amount))
+        .transpose()
+        .map_err(|e| invalid(3, e.to_string()))?;
+    let r#type: String = row.get(2)?;
+    let details = match (r#type.as_str(), amount) {
+        ("deposit", Some(amount)) => IncomingTxDetails::Deposit(amount),
+        ("withdrawal", Some(amount)) => IncomingTxDetails::Withdrawal(amount),
+        ("dispute", None) => IncomingTxDetails::Dispute,
+        ("resolve", None) => IncomingTxDetails::Resolve,
+        ("chargeback", None) => IncomingTxDetails::Chargeback,
+        ("lock", None) => IncomingTxDetails::Lock,
+        ("unlock", None) => IncomingTxDetails::Unlock,
+        (r#type, amount) => {
+            return Err(invalid(
+                2,
+                format!(
+                    "invalid transaction type `{}` with amount {:?}",
+                    r#type, amount
+                ),
+            ))
+        }
+    };
+    let state = match row.get::<_, String>(4)?.as_str() {
+        "complete" => TxState::Complete,
+        "under-dispute" => TxState::UnderDispute,
+        "resolved" => TxState::Resolved,
+        "charged-back" => TxState::ChargedBack,
+        state => return Err(invalid(4, format!("invalid transaction state `{}`", state))),
+    };
+
+    Ok(TxDetails {
+        original_tx: IncomingTx {
+            id: TxId(row.get(0)?),
+            account: AccountId(row.get(1)?),
+            details,
+        },
+        state,
+        disputes: row.get(5)?,
+        applied_at: row.get(6)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use tempdir::TempDir;
+
+    use crate::{
+        bank::TxCache,
+        tx::{
+            incoming::IncomingTx,
+            stored::{TxDetails, TxState},
+            TxId,
+        },
+    };
+
+    use super::SqliteTxCache;
+
+    #[test]
+    fn stored_transactions_read_back() {
+        let mut cache = SqliteTxCache::in_memory()
+            .unwrap()
+            .with_batch_size(NonZeroUsize::new(2).unwrap());
+        let deposit = TxDetails {
+            original_tx: IncomingTx::deposit(1, 2, "3.0400").unwrap(),
+            state: TxState::UnderDispute,
+            disputes: 1,
+            applied_at: 1,
+        };
+        let lock = TxDetails {
+            original_tx: IncomingTx::lock(2, 2),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: 2,
+        };
+
+        assert!(cache.is_empty().unwrap());
+        cache.store(deposit).unwrap();
+        assert!(!cache.is_empty().unwrap());
+        assert_eq!(cache.get_by_id(TxId(1)).unwrap(), Some(deposit));
+        cache.store(lock).unwrap();
+        cache.store(deposit.with_state(TxState::Resolved)).unwrap();
+        cache.flush().unwrap();
+        // 32 bytes of integers along with the type, amount and state of each of the three rows
+        assert_eq!(
+            cache.bytes_written(),
+            Some((32 + 7 + 6 + 13) + (32 + 4 + 8) + (32 + 7 + 6 + 8))
+        );
+
+        assert_eq!(
+            cache.get_by_id(TxId(1)).unwrap(),
+            Some(deposit.with_state(TxState::Resolved))
+        );
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), Some(lock));
+        assert_eq!(cache.get_by_id(TxId(3)).unwrap(), None);
+    }
+
+    #[test]
+    fn writes_not_flushed_are_rolled_back_on_drop() {
+        let temp_dir = TempDir::new("nesse-bank").unwrap();
+        let path = temp_dir.path().join("tx-cache.db");
+        let deposit = |id: u32| TxDetails {
+            original_tx: IncomingTx::deposit(id, 1, "1.0").unwrap(),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: id.into(),
+        };
+
+        let mut cache = SqliteTxCache::open(&path)
+            .unwrap()
+            .with_batch_size(NonZeroUsize::new(2).unwrap());
+        cache.store(deposit(1)).unwrap();
+        cache.store(deposit(2)).unwrap();
+        cache.store(deposit(3)).unwrap();
+        drop(cache);
+
+        let cache = SqliteTxCache::open(&path).unwrap();
+        assert_eq!(cache.bytes_written(), Some(0));
+        assert_eq!(cache.get_by_id(TxId(2)).unwrap(), Some(deposit(2)));
+        assert_eq!(cache.get_by_id(TxId(3)).unwrap(), None);
+    }
+}
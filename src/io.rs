@@ -1,90 +1,877 @@
-use std::{io::Read, num::ParseIntError};
+use std::{
+    fmt,
+    io::{BufRead, BufReader, Cursor, Read},
+    num::ParseIntError,
+    path::Path,
+};
 
-use csv::{StringRecordsIntoIter, Trim};
+use csv::{Position, StringRecord, StringRecordsIntoIter, Trim};
+use flate2::read::MultiGzDecoder;
+use serde::Deserialize;
 use thiserror::Error;
 
 use crate::{
     account::AccountId,
     tx::{
-        incoming::{IncomingTx, IncomingTxError},
+        incoming::{IncomingTx, IncomingTxDetails, IncomingTxError},
         TxId,
     },
 };
 
 #[derive(Error, Debug)]
-pub enum ParseError {
+pub enum ParseErrorKind {
     #[error("error parsing CSV")]
     Csv(#[from] csv::Error),
+    #[error("error parsing JSON")]
+    Json(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
     #[error("missing field `{0}`")]
     MissingField(&'static str),
+    #[error("missing column `{0}` in the header")]
+    MissingColumn(&'static str),
     #[error("error parsing integer")]
     IntField(#[from] ParseIntError),
     #[error("transaction error: {0}")]
     IncomingTransaction(#[from] IncomingTxError),
     #[error("unknown transaction type `{0}`")]
     UnknownTransactionType(String),
+    #[error("can't merge by timestamp: {0}")]
+    Unmergeable(String),
+}
+
+/// Error along with where it happened in the input and the offending record
+#[derive(Debug)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub position: Option<Position>,
+    /// Empty if the record couldn't be read as CSV, the whole line as a single field for JSON Lines
+    pub record: StringRecord,
+    /// The record as it was in the input, line terminator included, empty if it couldn't be read at all
+    pub raw: Vec<u8>,
+}
+
+impl ParseError {
+    /// Whether reading can go on past the offending record, I/O errors are likely to repeat
+    pub fn is_recoverable(&self) -> bool {
+        match &self.kind {
+            ParseErrorKind::Csv(e) => !e.is_io_error(),
+            ParseErrorKind::IO(_)
+            | ParseErrorKind::MissingColumn(_)
+            | ParseErrorKind::Unmergeable(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(position) = &self.position {
+            write!(f, " at line {}, byte {}", position.line(), position.byte())?;
+        }
+        if !self.record.is_empty() {
+            write!(
+                f,
+                ": `{}`",
+                self.record.iter().collect::<Vec<_>>().join(",")
+            )?;
+        } else if !self.raw.trim_ascii().is_empty() {
+            write!(f, ": `{}`", String::from_utf8_lossy(self.raw.trim_ascii()))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseError {
+    // `kind` is already a part of the message
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        std::error::Error::source(&self.kind)
+    }
+}
+
+/// Transactions read from the input along with where they came from
+pub trait TxSource: Iterator<Item = Result<IncomingTx, ParseError>> {
+    /// Name of the input the most recently read record came from, the file path or `-` for stdin
+    fn source(&self) -> &str;
+    /// Line number of the most recently read record
+    fn line(&self) -> u64;
+    /// Unix timestamp in seconds of the most recently read transaction, if the input has one
+    fn timestamp(&self) -> Option<u64>;
+    /// Who issued the most recently read administrative operation, only `lock` and `unlock` records have one
+    fn operator(&self) -> Option<&str>;
+    /// Header of the input the most recently read record came from as it was in the input, `None` if there's none
+    fn header(&self) -> Option<&[u8]> {
+        None
+    }
+    /// Whether records can have a timestamp at all, only known to be `false` once a header leaves it out
+    fn has_timestamps(&self) -> bool {
+        true
+    }
+}
+
+/// Keeps what was read from the input since the start of the current record, so that records can be taken as is
+struct RawCapture<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Position of `buf[0]` in the input
+    offset: u64,
+    /// Everything before this position is no longer needed
+    keep_from: u64,
+}
+
+impl<R: Read> RawCapture<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            offset: 0,
+            keep_from: 0,
+        }
+    }
+
+    /// Bytes between the two positions in the input, both after `keep_from`
+    fn raw(&self, start: u64, end: u64) -> &[u8] {
+        let index = |position: u64| position.saturating_sub(self.offset) as usize;
+        self.buf.get(index(start)..index(end)).unwrap_or_default()
+    }
+}
+
+impl<R: Read> Read for RawCapture<R> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(out)?;
+        // Dropped once per read rather than per record, reads are much larger than records
+        let unneeded = (self.keep_from.saturating_sub(self.offset) as usize).min(self.buf.len());
+        self.buf.drain(..unneeded);
+        self.offset += unneeded as u64;
+        self.buf.extend_from_slice(&out[..read]);
+        Ok(read)
+    }
+}
+
+/// Indices of the fields in CSV records, resolved by header names
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct Columns {
+    r#type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    operator: Option<usize>,
+}
+
+impl Columns {
+    /// Header names are matched case-insensitively, columns of other names are ignored
+    fn from_headers(headers: &StringRecord) -> Result<Self, ParseErrorKind> {
+        let find = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|header| names.iter().any(|name| header.eq_ignore_ascii_case(name)))
+        };
+        let required = |column: &'static str, names: &[&str]| {
+            find(names).ok_or(ParseErrorKind::MissingColumn(column))
+        };
+        Ok(Self {
+            r#type: required("type", &["type"])?,
+            client: required("client", &["client", "client_id"])?,
+            tx: required("tx", &["tx", "tx_id"])?,
+            // Only deposits and withdrawals need it, those are rejected one by one if there's none
+            amount: find(&["amount"]),
+            timestamp: find(&["timestamp"]),
+            operator: find(&["operator"]),
+        })
+    }
+}
+
+enum Header {
+    Unread,
+    Columns(Columns),
+    /// Reported once, there are no records to read without it
+    Invalid,
 }
 
 pub struct RecordsIter<R: Read> {
-    inner: StringRecordsIntoIter<R>,
+    inner: StringRecordsIntoIter<RawCapture<R>>,
+    source: String,
+    header: Header,
+    raw_header: Vec<u8>,
+    line: u64,
+    timestamp: Option<u64>,
+    operator: Option<String>,
+}
+
+impl<R: Read> RecordsIter<R> {
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            ..self
+        }
+    }
+
+    /// `None` if the input is empty
+    fn read_header(&mut self) -> Option<Result<Columns, ParseError>> {
+        let headers = match self.inner.reader_mut().headers() {
+            Ok(headers) if headers.is_empty() => return None,
+            Ok(headers) => headers.clone(),
+            Err(e) => {
+                let position = e.position().cloned();
+                let raw = self.take_raw(position.as_ref());
+                return Some(Err(ParseError {
+                    position,
+                    kind: e.into(),
+                    record: StringRecord::new(),
+                    raw,
+                }));
+            }
+        };
+        self.line = 1;
+        self.raw_header = self.take_raw(headers.position());
+        Some(Columns::from_headers(&headers).map_err(|kind| ParseError {
+            kind,
+            position: headers.position().cloned(),
+            raw: self.raw_header.clone(),
+            record: headers,
+        }))
+    }
+
+    /// Raw bytes from `start` up to the end of the record just read, nothing before the end is needed afterwards
+    fn take_raw(&mut self, start: Option<&Position>) -> Vec<u8> {
+        let end = self.inner.reader().position().byte();
+        let capture = self.inner.reader_mut().get_mut();
+        let raw = match start {
+            Some(start) => capture.raw(start.byte(), end).to_vec(),
+            None => Vec::new(),
+        };
+        capture.keep_from = end;
+        raw
+    }
+
+    /// Only errors need the raw record, the rest just let go of it
+    fn skip_raw(&mut self) {
+        let end = self.inner.reader().position().byte();
+        self.inner.reader_mut().get_mut().keep_from = end;
+    }
+}
+
+impl<R: Read> TxSource for RecordsIter<R> {
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Line number of the most recently read record, starting from 1 for the header
+    fn line(&self) -> u64 {
+        self.line
+    }
+
+    /// Unix timestamp in seconds of the most recently read transaction, from the optional `timestamp` column
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// From the `operator` column, which `lock` and `unlock` records can't do without
+    fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+
+    fn header(&self) -> Option<&[u8]> {
+        Some(self.raw_header.as_slice()).filter(|header| !header.is_empty())
+    }
+
+    fn has_timestamps(&self) -> bool {
+        match self.header {
+            Header::Columns(columns) => columns.timestamp.is_some(),
+            Header::Unread | Header::Invalid => true,
+        }
+    }
 }
 
 impl<R: Read> Iterator for RecordsIter<R> {
     type Item = Result<IncomingTx, ParseError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.inner
-            .next()
-            // Skip the last empty line
-            .filter(|r| {
-                r.as_ref()
-                    .map(|r| !r.is_empty() && r.get(0) != Some(""))
-                    .unwrap_or(true)
-            })
-            .map(|record| {
-                // I decided to parse fields manually because csv's serde implementation is wonky at times
-                // Also there would be more of the supporting code spread across multiple places
-                let record = record?;
-                let r#type = record.get(0).ok_or(ParseError::MissingField("type"))?;
-                let account = AccountId(
-                    record
-                        .get(1)
-                        .ok_or(ParseError::MissingField("client"))?
-                        .parse()?,
-                );
-                let id = TxId(
-                    record
-                        .get(2)
-                        .ok_or(ParseError::MissingField("tx"))?
-                        .parse()?,
-                );
-                match r#type {
-                    "deposit" => {
-                        let amount = record.get(3).ok_or(ParseError::MissingField("amount"))?;
-                        Ok(IncomingTx::deposit(id, account, amount)?)
-                    }
-                    "withdrawal" => {
-                        let amount = record.get(3).ok_or(ParseError::MissingField("amount"))?;
-                        Ok(IncomingTx::withdrawal(id, account, amount)?)
-                    }
-                    "dispute" => Ok(IncomingTx::dispute(id, account)),
-                    "resolve" => Ok(IncomingTx::resolve(id, account)),
-                    "chargeback" => Ok(IncomingTx::chargeback(id, account)),
-                    unknown_type => {
-                        Err(ParseError::UnknownTransactionType(unknown_type.to_owned()))
-                    }
+        let columns = match self.header {
+            Header::Columns(columns) => columns,
+            Header::Invalid => return None,
+            Header::Unread => match self.read_header()? {
+                Ok(columns) => {
+                    self.header = Header::Columns(columns);
+                    columns
                 }
-            })
+                Err(e) => {
+                    self.header = Header::Invalid;
+                    return Some(Err(e));
+                }
+            },
+        };
+        let record = match self.inner.next()? {
+            Ok(record) => record,
+            Err(e) => {
+                let position = e.position().cloned();
+                if let Some(position) = &position {
+                    self.line = position.line();
+                }
+                let raw = self.take_raw(position.as_ref());
+                return Some(Err(ParseError {
+                    kind: e.into(),
+                    position,
+                    record: StringRecord::new(),
+                    raw,
+                }));
+            }
+        };
+        if let Some(position) = record.position() {
+            self.line = position.line();
+        }
+        // Skip the last empty line
+        if record.iter().all(str::is_empty) {
+            return None;
+        }
+        match parse_record(&record, &columns) {
+            Ok((tx, timestamp, operator)) => {
+                self.skip_raw();
+                self.timestamp = timestamp;
+                self.operator = operator;
+                Some(Ok(tx))
+            }
+            Err(kind) => Some(Err(ParseError {
+                kind,
+                position: record.position().cloned(),
+                raw: self.take_raw(record.position()),
+                record,
+            })),
+        }
     }
 }
 
+/// Transaction read from a record along with its timestamp and operator
+type ParsedRecord = (IncomingTx, Option<u64>, Option<String>);
+
+fn parse_record(record: &StringRecord, columns: &Columns) -> Result<ParsedRecord, ParseErrorKind> {
+    // I decided to parse fields manually because csv's serde implementation is wonky at times
+    // Also there would be more of the supporting code spread across multiple places
+    let r#type = record
+        .get(columns.r#type)
+        .ok_or(ParseErrorKind::MissingField("type"))?;
+    let account = AccountId(
+        record
+            .get(columns.client)
+            .ok_or(ParseErrorKind::MissingField("client"))?
+            .parse()?,
+    );
+    let id = TxId(
+        record
+            .get(columns.tx)
+            .ok_or(ParseErrorKind::MissingField("tx"))?
+            .parse()?,
+    );
+    let timestamp = match columns.timestamp.and_then(|i| record.get(i)) {
+        Some(timestamp) if !timestamp.is_empty() => Some(timestamp.parse()?),
+        _ => None,
+    };
+    let amount = columns.amount.and_then(|i| record.get(i));
+    let tx = new_tx(r#type, id, account, amount)?;
+    let operator = columns.operator.and_then(|i| record.get(i));
+    Ok((tx, timestamp, admin_operator(&tx, timestamp, operator)?))
+}
+
+/// Administrative operations have to say who issued them and when, other transactions don't keep an operator
+fn admin_operator(
+    tx: &IncomingTx,
+    timestamp: Option<u64>,
+    operator: Option<&str>,
+) -> Result<Option<String>, ParseErrorKind> {
+    if !matches!(
+        tx.details,
+        IncomingTxDetails::Lock | IncomingTxDetails::Unlock
+    ) {
+        return Ok(None);
+    }
+    timestamp.ok_or(ParseErrorKind::MissingField("timestamp"))?;
+    match operator {
+        Some(operator) if !operator.is_empty() => Ok(Some(operator.to_owned())),
+        _ => Err(ParseErrorKind::MissingField("operator")),
+    }
+}
+
+/// Builds the transaction out of fields read in any input format, `amount` is only required for deposits and withdrawals
+fn new_tx(
+    r#type: &str,
+    id: TxId,
+    account: AccountId,
+    amount: Option<&str>,
+) -> Result<IncomingTx, ParseErrorKind> {
+    let amount = || amount.ok_or(ParseErrorKind::MissingField("amount"));
+    let tx = match r#type {
+        "deposit" => IncomingTx::deposit(id, account, amount()?)?,
+        "withdrawal" => IncomingTx::withdrawal(id, account, amount()?)?,
+        "dispute" => IncomingTx::dispute(id, account),
+        "resolve" => IncomingTx::resolve(id, account),
+        "chargeback" => IncomingTx::chargeback(id, account),
+        "lock" => IncomingTx::lock(id, account),
+        "unlock" => IncomingTx::unlock(id, account),
+        unknown_type => {
+            return Err(ParseErrorKind::UnknownTransactionType(
+                unknown_type.to_owned(),
+            ))
+        }
+    };
+    Ok(tx)
+}
+
 pub fn csv_reader<R: Read>(reader: R) -> RecordsIter<R> {
     RecordsIter {
         inner: csv::ReaderBuilder::new()
             .has_headers(true)
             .trim(Trim::All)
             .flexible(true) // Otherwise we get errors on empty lines
-            .from_reader(reader)
+            .from_reader(RawCapture::new(reader))
             .into_records(),
+        source: "-".to_owned(),
+        header: Header::Unread,
+        raw_header: Vec::new(),
+        line: 0,
+        timestamp: None,
+        operator: None,
+    }
+}
+
+/// A single line of JSON Lines input, fields are named after the CSV columns
+#[derive(Deserialize)]
+struct JsonRecord {
+    r#type: String,
+    #[serde(alias = "client_id")]
+    client: u16,
+    #[serde(alias = "tx_id")]
+    tx: u32,
+    #[serde(default)]
+    amount: Option<JsonAmount>,
+    #[serde(default)]
+    timestamp: Option<u64>,
+    #[serde(default)]
+    operator: Option<String>,
+}
+
+/// Strings and numbers alike are taken as written, numbers keep all of their digits with serde_json's
+/// `arbitrary_precision`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonAmount {
+    Text(String),
+    Number(serde_json::Number),
+}
+
+impl JsonAmount {
+    fn into_string(self) -> String {
+        match self {
+            JsonAmount::Text(amount) => amount,
+            JsonAmount::Number(amount) => amount.to_string(),
+        }
+    }
+}
+
+pub struct JsonLinesIter<R: Read> {
+    inner: BufReader<R>,
+    source: String,
+    buf: Vec<u8>,
+    line: u64,
+    byte: u64,
+    timestamp: Option<u64>,
+    operator: Option<String>,
+}
+
+impl<R: Read> JsonLinesIter<R> {
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            ..self
+        }
+    }
+}
+
+impl<R: Read> TxSource for JsonLinesIter<R> {
+    fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Line number of the most recently read record, starting from 1 as there's no header
+    fn line(&self) -> u64 {
+        self.line
+    }
+
+    /// Unix timestamp in seconds of the most recently read transaction, from the optional `timestamp` field
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// From the `operator` field, which `lock` and `unlock` records can't do without
+    fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+}
+
+impl<R: Read> Iterator for JsonLinesIter<R> {
+    type Item = Result<IncomingTx, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buf.clear();
+            let mut position = Position::new();
+            position.set_line(self.line + 1).set_byte(self.byte);
+            let read = match self.inner.read_until(b'\n', &mut self.buf) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => {
+                    return Some(Err(ParseError {
+                        kind: e.into(),
+                        position: Some(position),
+                        record: StringRecord::new(),
+                        raw: Vec::new(),
+                    }))
+                }
+            };
+            self.line += 1;
+            self.byte += read as u64;
+
+            let line = self.buf.trim_ascii();
+            // Blank lines are as harmless as the trailing empty line of CSV
+            if line.is_empty() {
+                continue;
+            }
+            return Some(
+                parse_json_record(line)
+                    .map(|(tx, timestamp, operator)| {
+                        self.timestamp = timestamp;
+                        self.operator = operator;
+                        tx
+                    })
+                    .map_err(|kind| ParseError {
+                        kind,
+                        position: Some(position),
+                        record: StringRecord::from(vec![String::from_utf8_lossy(line)]),
+                        raw: self.buf.clone(),
+                    }),
+            );
+        }
+    }
+}
+
+fn parse_json_record(line: &[u8]) -> Result<ParsedRecord, ParseErrorKind> {
+    let record: JsonRecord = serde_json::from_slice(line)?;
+    let amount = record.amount.map(JsonAmount::into_string);
+    let tx = new_tx(
+        &record.r#type,
+        TxId(record.tx),
+        AccountId(record.client),
+        amount.as_deref(),
+    )?;
+    let operator = admin_operator(&tx, record.timestamp, record.operator.as_deref())?;
+    Ok((tx, record.timestamp, operator))
+}
+
+/// Reads one JSON object per line, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`
+pub fn jsonl_reader<R: Read>(reader: R) -> JsonLinesIter<R> {
+    JsonLinesIter {
+        inner: BufReader::new(reader),
+        source: "-".to_owned(),
+        buf: Vec::new(),
+        line: 0,
+        byte: 0,
+        timestamp: None,
+        operator: None,
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Decompresses gzip and zstd input on the fly, recognized by their magic bytes, and passes anything else through
+pub fn decompressed<'r>(mut reader: impl Read + 'r) -> std::io::Result<Box<dyn Read + 'r>> {
+    let mut magic = [0; 4];
+    let mut len = 0;
+    // A pipe may hand the magic bytes over in pieces
+    while len < magic.len() {
+        match reader.read(&mut magic[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let is = |expected: &[u8]| magic[..len].starts_with(expected);
+    let reader = Cursor::new(magic).take(len as u64).chain(reader);
+
+    Ok(if is(GZIP_MAGIC) {
+        // Concatenated gzip files are still a valid gzip file
+        Box::new(MultiGzDecoder::new(reader))
+    } else if is(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::new(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    JsonLines,
+}
+
+impl InputFormat {
+    /// Guesses the format by the file extension, the one before `.gz` or `.zst` for compressed files, `None` if it's
+    /// not a known one
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        let path = match path.extension()?.to_str()? {
+            "gz" | "zst" => Path::new(path.file_stem()?),
+            _ => path,
+        };
+        match path.extension()?.to_str()? {
+            "csv" => Some(Self::Csv),
+            "jsonl" | "ndjson" => Some(Self::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Transactions read in either of the input formats
+pub enum TxReader<R: Read> {
+    Csv(RecordsIter<R>),
+    JsonLines(JsonLinesIter<R>),
+}
+
+impl<R: Read> TxReader<R> {
+    pub fn new(format: InputFormat, reader: R) -> Self {
+        match format {
+            InputFormat::Csv => Self::Csv(csv_reader(reader)),
+            InputFormat::JsonLines => Self::JsonLines(jsonl_reader(reader)),
+        }
+    }
+
+    /// Names the input in reports, `-` stands for stdin and is the default
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        match self {
+            Self::Csv(records) => Self::Csv(records.with_source(source)),
+            Self::JsonLines(records) => Self::JsonLines(records.with_source(source)),
+        }
+    }
+}
+
+impl<R: Read> TxSource for TxReader<R> {
+    fn source(&self) -> &str {
+        match self {
+            TxReader::Csv(records) => records.source(),
+            TxReader::JsonLines(records) => records.source(),
+        }
+    }
+
+    fn line(&self) -> u64 {
+        match self {
+            TxReader::Csv(records) => records.line(),
+            TxReader::JsonLines(records) => records.line(),
+        }
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        match self {
+            TxReader::Csv(records) => records.timestamp(),
+            TxReader::JsonLines(records) => records.timestamp(),
+        }
+    }
+
+    fn operator(&self) -> Option<&str> {
+        match self {
+            TxReader::Csv(records) => records.operator(),
+            TxReader::JsonLines(records) => records.operator(),
+        }
+    }
+
+    fn header(&self) -> Option<&[u8]> {
+        match self {
+            TxReader::Csv(records) => records.header(),
+            TxReader::JsonLines(records) => records.header(),
+        }
+    }
+
+    fn has_timestamps(&self) -> bool {
+        match self {
+            TxReader::Csv(records) => records.has_timestamps(),
+            TxReader::JsonLines(records) => records.has_timestamps(),
+        }
+    }
+}
+
+impl<R: Read> Iterator for TxReader<R> {
+    type Item = Result<IncomingTx, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            TxReader::Csv(records) => records.next(),
+            TxReader::JsonLines(records) => records.next(),
+        }
+    }
+}
+
+/// Record read ahead from one of the inputs of [`MultiReader`]
+struct Head {
+    record: Result<IncomingTx, ParseError>,
+    line: u64,
+    timestamp: Option<u64>,
+    operator: Option<String>,
+    /// Timestamp to merge by, records without one and errors come right after the previous record of the input
+    merge_key: u64,
+}
+
+/// Reads several inputs one after another, or merges them by the `timestamp` column
+///
+/// Merging expects every input to have the `timestamp` column and be ordered by it already, records with equal
+/// timestamps are taken in the order of the inputs. An input out of order stops the merge with an error, as does a record
+/// without a timestamp before any record of its input has one
+pub struct MultiReader<S: TxSource> {
+    inputs: Vec<S>,
+    heads: Vec<Option<Head>>,
+    /// Merge key of the record most recently read ahead from each input, `None` until one has a timestamp
+    merge_keys: Vec<Option<u64>>,
+    merge_by_timestamp: bool,
+    input: usize,
+    line: u64,
+    timestamp: Option<u64>,
+    operator: Option<String>,
+}
+
+impl<S: TxSource> MultiReader<S> {
+    pub fn new(inputs: Vec<S>) -> Self {
+        Self {
+            heads: inputs.iter().map(|_| None).collect(),
+            merge_keys: vec![None; inputs.len()],
+            inputs,
+            merge_by_timestamp: false,
+            input: 0,
+            line: 0,
+            timestamp: None,
+            operator: None,
+        }
+    }
+
+    pub fn with_merge_by_timestamp(self, merge_by_timestamp: bool) -> Self {
+        Self {
+            merge_by_timestamp,
+            ..self
+        }
+    }
+
+    /// Index of the input the most recently read record came from
+    pub fn input(&self) -> usize {
+        self.input
+    }
+
+    fn read_ahead(&mut self, input: usize) {
+        let inner = &mut self.inputs[input];
+        let merge_key = &mut self.merge_keys[input];
+        let merge_by_timestamp = self.merge_by_timestamp;
+        self.heads[input] = inner.next().map(|record| {
+            let timestamp = inner.timestamp().filter(|_| record.is_ok());
+            let record = match record {
+                Ok(_) if merge_by_timestamp => match check_merge_order(inner, *merge_key) {
+                    Some(message) => Err(ParseError {
+                        kind: ParseErrorKind::Unmergeable(message),
+                        position: None,
+                        record: StringRecord::new(),
+                        raw: Vec::new(),
+                    }),
+                    None => record,
+                },
+                _ => record,
+            };
+            *merge_key = timestamp.or(*merge_key);
+            Head {
+                record,
+                line: inner.line(),
+                timestamp,
+                operator: inner.operator().map(str::to_owned),
+                merge_key: merge_key.unwrap_or_default(),
+            }
+        });
+    }
+}
+
+/// Why the record just read from `input` can't be merged after the one with `previous` timestamp, if it can't
+fn check_merge_order(input: &impl TxSource, previous: Option<u64>) -> Option<String> {
+    let (source, line) = (input.source(), input.line());
+    if !input.has_timestamps() {
+        return Some(format!("{} has no `timestamp` column", source));
+    }
+    match (input.timestamp(), previous) {
+        (None, None) => Some(format!(
+            "line {} of {} has no timestamp and no record before it does",
+            line, source
+        )),
+        (Some(timestamp), Some(previous)) if timestamp < previous => Some(format!(
+            "timestamp {} on line {} of {} is earlier than {} before it",
+            timestamp, line, source, previous
+        )),
+        _ => None,
+    }
+}
+
+impl<S: TxSource> Iterator for MultiReader<S> {
+    type Item = Result<IncomingTx, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.merge_by_timestamp {
+            for input in 0..self.inputs.len() {
+                if self.heads[input].is_none() {
+                    self.read_ahead(input);
+                }
+            }
+        } else {
+            // Later inputs aren't touched until the earlier ones run out
+            while self.input < self.inputs.len() {
+                if self.heads[self.input].is_none() {
+                    self.read_ahead(self.input);
+                }
+                if self.heads[self.input].is_some() {
+                    break;
+                }
+                self.input += 1;
+            }
+        }
+
+        let (input, _) = self
+            .heads
+            .iter()
+            .enumerate()
+            .filter_map(|(input, head)| Some((input, head.as_ref()?.merge_key)))
+            .min_by_key(|&(input, merge_key)| (merge_key, input))?;
+        let head = self.heads[input].take()?;
+        self.input = input;
+        self.line = head.line;
+        self.timestamp = head.timestamp;
+        self.operator = head.operator;
+        Some(head.record)
+    }
+}
+
+impl<S: TxSource> TxSource for MultiReader<S> {
+    fn source(&self) -> &str {
+        self.inputs
+            .get(self.input)
+            .map_or("-", |input| input.source())
+    }
+
+    /// Line number of the most recently read record in its own input
+    fn line(&self) -> u64 {
+        self.line
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    fn operator(&self) -> Option<&str> {
+        self.operator.as_deref()
+    }
+
+    fn header(&self) -> Option<&[u8]> {
+        self.inputs.get(self.input)?.header()
     }
 }
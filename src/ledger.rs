@@ -0,0 +1,99 @@
+use std::{collections::BTreeMap, io::Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    account::{Account, AccountId},
+    tx::{incoming::IncomingTx, incoming::IncomingTxDetails, stored::TxDetails, TxId},
+    Money,
+};
+
+/// Side of a client's funds a journal entry moves money between
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LedgerAccount {
+    /// Funds the client can use
+    Available,
+    /// Funds held by ongoing disputes
+    Held,
+    /// Money outside of the bank, source of deposits and destination of withdrawals and chargebacks
+    External,
+}
+
+/// Movement of a non-negative `amount` from `credit` to `debit`, the two sides always balance out
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct JournalEntry {
+    /// Input the transaction came from, the file path or `-` for stdin
+    #[serde(default)]
+    pub source: String,
+    /// Line of the transaction within its input
+    #[serde(default)]
+    pub line: u64,
+    pub tx: TxId,
+    pub r#type: String,
+    pub client: AccountId,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Money,
+}
+
+impl JournalEntry {
+    /// Entry for a transaction read at `line` of `source` that has just been applied and resulted in `applied`, `None`
+    /// if no money moved
+    pub fn for_tx(source: &str, line: u64, tx: &IncomingTx, applied: &TxDetails) -> Option<Self> {
+        use LedgerAccount::*;
+
+        let balance_effect = applied.original_tx.details.balance_effect()?;
+        let (debit, credit) = match tx.details {
+            IncomingTxDetails::Deposit(_) | IncomingTxDetails::Withdrawal(_) => {
+                (Available, External)
+            }
+            IncomingTxDetails::Dispute => (Held, Available),
+            IncomingTxDetails::Resolve => (Available, Held),
+            IncomingTxDetails::Chargeback => (External, Held),
+            IncomingTxDetails::Lock | IncomingTxDetails::Unlock => return None,
+        };
+        let (debit, credit, amount) = if balance_effect.is_sign_negative() {
+            (credit, debit, -balance_effect)
+        } else {
+            (debit, credit, balance_effect)
+        };
+
+        Some(Self {
+            source: source.to_owned(),
+            line,
+            tx: tx.id,
+            r#type: tx.details.name().to_owned(),
+            client: tx.account,
+            debit,
+            credit,
+            amount,
+        })
+    }
+}
+
+pub fn read_journal(reader: impl Read) -> impl Iterator<Item = Result<JournalEntry, csv::Error>> {
+    csv::ReaderBuilder::new()
+        .from_reader(reader)
+        .into_deserialize()
+}
+
+/// Balances of every client the journal mentions, locked state isn't a movement of money so it's left as is
+pub fn rebuild_accounts(
+    journal: impl IntoIterator<Item = JournalEntry>,
+) -> BTreeMap<AccountId, Account> {
+    let mut accounts = BTreeMap::<AccountId, Account>::new();
+
+    for entry in journal {
+        let account = accounts.entry(entry.client).or_default();
+        for (side, amount) in [(entry.debit, entry.amount), (entry.credit, -entry.amount)] {
+            match side {
+                LedgerAccount::Available => account.balance += amount,
+                LedgerAccount::Held => account.held += amount,
+                LedgerAccount::External => {}
+            }
+        }
+    }
+
+    accounts
+}
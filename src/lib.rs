@@ -2,7 +2,12 @@ use rust_decimal::Decimal;
 
 pub mod account;
 pub mod bank;
+pub mod cache;
 pub mod io;
+pub mod ledger;
+pub mod metrics;
+pub mod policy;
+pub mod report;
 pub mod tx;
 pub mod util;
 
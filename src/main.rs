@@ -1,10 +1,26 @@
+use anyhow::bail;
 use clap::{ArgEnum, Parser};
-use kv::{Config, Integer, Raw, Store};
 use nesse_bank::{
-    bank::{InMemoryTxCache, OnDiskTxCache, TxCache},
-    util::{historic_run, write_state},
+    bank::{
+        begin_run, has_saved_state, AccountStore, Bank, InMemoryAccountStore, InMemoryTxCache,
+        TxCache, DEFAULT_BATCH_SIZE,
+    },
+    cache::{compact::CompactTxCache, lru::LruTxCache, sqlite::SqliteTxCache},
+    io::{decompressed, InputFormat, MultiReader, ParseError, TxReader},
+    policy::Policy,
+    report::{QuarantineReport, Report, ReportFormat},
+    util::{
+        disk_account_store, disk_cache, historic_run_from, write_state_as, OnParseError,
+        OutputFormat, RunOptions, RunSummary,
+    },
+};
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{BufWriter, Read},
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
 };
-use std::{fmt::Debug, fs::File, path::PathBuf};
 use tempdir::TempDir;
 
 /// This program does historic run over a list of transactions and outputs the final state of accounts
@@ -13,33 +29,400 @@ struct Args {
     // transaction cache backend
     #[clap(arg_enum, short, long, default_value = "disk")]
     cache_backend: TxCacheBackend,
-    /// input csv file with columns: type, client, tx, amount
-    input_file: PathBuf,
+    /// where the cache backend keeps transactions, a directory for disk and a file for sqlite;
+    /// inside `--state-dir` if it's set and temporary otherwise, must be empty unless `--state-dir` resumes it
+    #[clap(long)]
+    cache_path: Option<PathBuf>,
+    /// only keep amounts of transactions that can be disputed under the policy, the transactions themselves are still
+    /// kept; saves memory only if the policy doesn't let withdrawals be disputed, used with `-c compact`
+    #[clap(long)]
+    disputable_amounts_only: bool,
+    /// number of transactions the disk and sqlite caches, as well as accounts the disk store, write at once
+    #[clap(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    cache_batch_size: NonZeroUsize,
+    /// where accounts are kept, the disk store is for client ids too many to fit in memory; runs resumed from the same
+    /// `--state-dir` have to use the same store
+    #[clap(arg_enum, long, default_value = "memory")]
+    account_store: AccountStoreBackend,
+    /// keep this many recently used transactions in memory in front of the cache backend
+    #[clap(long)]
+    hot_cache: Option<NonZeroUsize>,
+    /// directory to resume the state of accounts and the cache from and save them to after the run
+    #[clap(long)]
+    state_dir: Option<PathBuf>,
+    /// JSON file with business rules overriding the defaults, see `Policy` for the fields
+    #[clap(long)]
+    policy: Option<PathBuf>,
+    /// write rejected transactions along with rejection reasons to this file
+    #[clap(long)]
+    rejected_output: Option<PathBuf>,
+    /// format of the rejected transactions report
+    #[clap(arg_enum, long, default_value = "csv")]
+    rejected_format: ReportFileFormat,
+    /// write every change of accounts' locked state, along with the transaction that caused it, to this file
+    #[clap(long)]
+    audit_output: Option<PathBuf>,
+    /// format of the locked state changes report
+    #[clap(arg_enum, long, default_value = "csv")]
+    audit_format: ReportFileFormat,
+    /// write a double-entry journal of every movement of money to this CSV file
+    #[clap(long)]
+    journal_output: Option<PathBuf>,
+    /// format of the final state of accounts written to stdout
+    #[clap(arg_enum, long, default_value = "csv")]
+    output_format: OutputFileFormat,
+    /// print counts of applied and rejected transactions, cache and disk usage and throughput to stderr
+    #[clap(long)]
+    stats: bool,
+    /// write the same metrics in Prometheus text exposition format to this file
+    #[clap(long)]
+    metrics_output: Option<PathBuf>,
+    /// what to do with input rows that can't be parsed
+    #[clap(arg_enum, long, default_value = "abort")]
+    on_parse_error: ParseErrorMode,
+    /// write input rows that can't be parsed to this file, used with `--on-parse-error=quarantine`
+    #[clap(long, required_if_eq("on-parse-error", "quarantine"))]
+    quarantine_output: Option<PathBuf>,
+    /// format of the input files, guessed by their extensions (.jsonl or .ndjson for JSON Lines) and CSV otherwise
+    #[clap(arg_enum, long)]
+    input_format: Option<InputFileFormat>,
+    /// merge the input files by their timestamp column instead of reading them one after another, each file has to be
+    /// ordered by timestamp already
+    #[clap(long)]
+    merge_by_timestamp: bool,
+    /// input csv files with columns found by header in any order: type, client (or client_id), tx (or tx_id), amount and
+    /// optionally timestamp in Unix seconds, others are ignored; types are deposit, withdrawal, dispute, resolve,
+    /// chargeback, lock and unlock. JSON Lines input has fields of the same names. `-` reads stdin, at most once
+    #[clap(required = true)]
+    input_files: Vec<PathBuf>,
 }
 
 #[derive(ArgEnum, Clone, Debug)]
 #[clap(rename_all = "lower")]
 enum TxCacheBackend {
+    Memory,
+    /// in memory, packed by dense transaction ids
+    Compact,
+    Disk,
+    Sqlite,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+#[clap(rename_all = "lower")]
+enum AccountStoreBackend {
     Memory,
     Disk,
 }
 
+#[derive(ArgEnum, Clone, Debug)]
+#[clap(rename_all = "lower")]
+enum ReportFileFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+#[clap(rename_all = "lower")]
+enum InputFileFormat {
+    Csv,
+    Jsonl,
+}
+
+#[derive(ArgEnum, Clone, Debug)]
+#[clap(rename_all = "lower")]
+enum OutputFileFormat {
+    Csv,
+    Json,
+    Jsonl,
+    /// aligned columns for reading in a terminal
+    Table,
+}
+
+#[derive(ArgEnum, Clone, Debug, PartialEq, Eq)]
+#[clap(rename_all = "lower")]
+enum ParseErrorMode {
+    Abort,
+    Skip,
+    Quarantine,
+}
+
+impl From<InputFileFormat> for InputFormat {
+    fn from(format: InputFileFormat) -> Self {
+        match format {
+            InputFileFormat::Csv => InputFormat::Csv,
+            InputFileFormat::Jsonl => InputFormat::JsonLines,
+        }
+    }
+}
+
+impl From<OutputFileFormat> for OutputFormat {
+    fn from(format: OutputFileFormat) -> Self {
+        match format {
+            OutputFileFormat::Csv => OutputFormat::Csv,
+            OutputFileFormat::Json => OutputFormat::Json,
+            OutputFileFormat::Jsonl => OutputFormat::JsonLines,
+            OutputFileFormat::Table => OutputFormat::Table,
+        }
+    }
+}
+
+impl From<ReportFileFormat> for ReportFormat {
+    fn from(format: ReportFileFormat) -> Self {
+        match format {
+            ReportFileFormat::Csv => ReportFormat::Csv,
+            ReportFileFormat::Jsonl => ReportFormat::JsonLines,
+        }
+    }
+}
+
 fn main() -> Result<(), anyhow::Error> {
     let args = Args::parse();
 
-    let cache: Box<dyn TxCache> = match args.cache_backend {
-        TxCacheBackend::Memory => Box::new(InMemoryTxCache::default()),
-        TxCacheBackend::Disk => Box::new({
-            let temp_dir = TempDir::new("nesse-bank")?;
-            let store_cfg = Config::new(temp_dir.path());
-            let store = Store::new(store_cfg)?;
-            let bucket = store.bucket::<Integer, Raw>(Some("tx"))?;
-            OnDiskTxCache::new(bucket)
-        }),
+    if let Some(state_dir) = &args.state_dir {
+        fs::create_dir_all(state_dir)?;
+    }
+
+    let policy = match &args.policy {
+        Some(path) => serde_json::from_reader(File::open(path)?)?,
+        None => Policy::default(),
+    };
+
+    // Lives until the end of the run, on-disk stores are deleted along with it
+    let mut temp_dir: Option<TempDir> = None;
+    let mut state_path = |name: &str| -> Result<PathBuf, std::io::Error> {
+        Ok(match (&args.state_dir, &mut temp_dir) {
+            (Some(state_dir), _) => state_dir.join(name),
+            (None, Some(temp_dir)) => temp_dir.path().join(name),
+            (None, temp_dir @ None) => temp_dir
+                .insert(TempDir::new("nesse-bank")?)
+                .path()
+                .join(name),
+        })
+    };
+    let in_memory = matches!(
+        args.cache_backend,
+        TxCacheBackend::Memory | TxCacheBackend::Compact
+    );
+    if in_memory && (args.state_dir.is_some() || args.cache_path.is_some()) {
+        bail!("in-memory cache backends can't be saved, use disk or sqlite");
+    }
+    if args.disputable_amounts_only && !matches!(args.cache_backend, TxCacheBackend::Compact) {
+        bail!("--disputable-amounts-only is only supported by the compact cache backend");
+    }
+    // Each input holds its own lock on stdin, a second one would wait for the first forever
+    if args
+        .input_files
+        .iter()
+        .filter(|path| path.as_os_str() == "-")
+        .count()
+        > 1
+    {
+        bail!("stdin can only be read once, `-` is given more than once");
+    }
+
+    let cache_path = match (&args.cache_backend, &args.cache_path) {
+        (TxCacheBackend::Memory | TxCacheBackend::Compact, _) => None,
+        (_, Some(path)) => Some(path.clone()),
+        (TxCacheBackend::Disk, None) => Some(state_path("tx-cache")?),
+        (TxCacheBackend::Sqlite, None) => Some(state_path("tx-cache.db")?),
+    };
+    let accounts_path = match args.account_store {
+        AccountStoreBackend::Memory => None,
+        AccountStoreBackend::Disk => Some(state_path("accounts")?),
+    };
+    // Before any of the stores is opened
+    if let Some(state_dir) = &args.state_dir {
+        let mut stores: Vec<PathBuf> = accounts_path.iter().cloned().collect();
+        match (&args.cache_backend, &cache_path) {
+            (TxCacheBackend::Sqlite, Some(path)) => stores.extend(SqliteTxCache::files(path)),
+            (_, Some(path)) => stores.push(path.clone()),
+            (_, None) => {}
+        }
+        begin_run(state_dir, &stores)?;
+    }
+
+    let mut rejected = match &args.rejected_output {
+        Some(path) => Some(Report::new(
+            args.rejected_format.clone().into(),
+            BufWriter::new(File::create(path)?),
+        )),
+        None => None,
+    };
+
+    let mut audit = match &args.audit_output {
+        Some(path) => Some(Report::new(
+            args.audit_format.clone().into(),
+            BufWriter::new(File::create(path)?),
+        )),
+        None => None,
+    };
+
+    let mut quarantine = match &args.quarantine_output {
+        Some(path) => Some(QuarantineReport::new(BufWriter::new(File::create(path)?))),
+        None => None,
+    };
+
+    let mut journal = match &args.journal_output {
+        Some(path) => Some(Report::new(
+            ReportFormat::Csv,
+            BufWriter::new(File::create(path)?),
+        )),
+        None => None,
+    };
+
+    let accounts: Box<dyn AccountStore> = match (accounts_path, &args.state_dir) {
+        (None, Some(state_dir)) => Box::new(InMemoryAccountStore::open(state_dir)?),
+        (None, None) => Box::new(InMemoryAccountStore::default()),
+        (Some(path), _) => {
+            Box::new(disk_account_store(&path)?.with_batch_size(args.cache_batch_size))
+        }
+    };
+
+    let mut log_skipped = |source: &str, e: &ParseError| match args.on_parse_error {
+        ParseErrorMode::Quarantine => eprintln!("quarantining {}: {}", source, e),
+        _ => eprintln!("skipping {}: {}", source, e),
+    };
+    let options = RunOptions {
+        on_parse_error: match (&args.on_parse_error, quarantine.as_mut()) {
+            (ParseErrorMode::Abort, _) => OnParseError::Abort,
+            (ParseErrorMode::Skip, _) => OnParseError::Skip,
+            (ParseErrorMode::Quarantine, Some(quarantine)) => OnParseError::Quarantine(quarantine),
+            (ParseErrorMode::Quarantine, None) => unreachable!("enforced by clap"),
+        },
+        on_skipped: Some(&mut log_skipped),
+        rejected: rejected.as_mut().map(|r| r as _),
+        audit: audit.as_mut().map(|r| r as _),
+        journal: journal.as_mut().map(|r| r as _),
+        // Read per input in `open_inputs`
+        ..Default::default()
+    };
+
+    let summary = match (&args.cache_backend, cache_path) {
+        (TxCacheBackend::Memory, _) => {
+            run(InMemoryTxCache::default(), accounts, policy, options, &args)?
+        }
+        (TxCacheBackend::Compact, _) => {
+            let cache = if args.disputable_amounts_only {
+                CompactTxCache::default().with_disputable_amounts_only(policy)
+            } else {
+                CompactTxCache::default()
+            };
+            run(cache, accounts, policy, options, &args)?
+        }
+        (TxCacheBackend::Disk, Some(path)) => {
+            let cache = disk_cache(&path)?.with_batch_size(args.cache_batch_size);
+            ensure_not_stale(cache.is_empty(), &path, &args)?;
+            run(cache, accounts, policy, options, &args)?
+        }
+        (TxCacheBackend::Sqlite, Some(path)) => {
+            let cache = SqliteTxCache::open(&path)?.with_batch_size(args.cache_batch_size);
+            ensure_not_stale(cache.is_empty()?, &path, &args)?;
+            run(cache, accounts, policy, options, &args)?
+        }
+        (TxCacheBackend::Disk | TxCacheBackend::Sqlite, None) => unreachable!("set above"),
     };
 
-    let state = historic_run(File::open(args.input_file)?, cache)?;
-    write_state(state, std::io::stdout())?;
+    for report in [rejected, audit, journal].iter_mut().flatten() {
+        report.flush()?;
+    }
+    if let Some(mut quarantine) = quarantine {
+        quarantine.flush()?;
+    }
+    if summary.skipped > 0 {
+        eprintln!("skipped {} unparseable rows", summary.skipped);
+    }
 
     Ok(())
 }
+
+/// Transactions found in the cache only belong to this run if it resumes the state saved along with them, otherwise
+/// they would be taken for duplicates of the new ones
+fn ensure_not_stale(is_empty: bool, path: &Path, args: &Args) -> Result<(), anyhow::Error> {
+    let resuming = args.state_dir.as_deref().is_some_and(has_saved_state);
+    if !is_empty && !resuming {
+        bail!(
+            "cache {} holds transactions of an earlier run, resume it with --state-dir or remove it",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Runs over the input with `cache` behind the hot cache if there's one, the cache type is known statically so that
+/// lookups aren't dispatched dynamically
+fn run<C: TxCache>(
+    cache: C,
+    accounts: Box<dyn AccountStore>,
+    policy: Policy,
+    options: RunOptions,
+    args: &Args,
+) -> Result<RunSummary, anyhow::Error> {
+    match args.hot_cache {
+        Some(capacity) => run_with_cache(
+            LruTxCache::new(cache, capacity),
+            accounts,
+            policy,
+            options,
+            args,
+        ),
+        None => run_with_cache(cache, accounts, policy, options, args),
+    }
+}
+
+fn run_with_cache<C: TxCache>(
+    cache: C,
+    accounts: Box<dyn AccountStore>,
+    policy: Policy,
+    options: RunOptions,
+    args: &Args,
+) -> Result<RunSummary, anyhow::Error> {
+    let bank = match &args.state_dir {
+        Some(state_dir) => Bank::open(cache, accounts, state_dir)?,
+        None => Bank::with_stores(cache, accounts),
+    }
+    .with_policy(policy);
+
+    let (mut state, summary) = historic_run_from(open_inputs(args)?, bank, options)?;
+    if let Some(state_dir) = &args.state_dir {
+        state.save(state_dir)?;
+    }
+    let metrics = state.metrics();
+    if args.stats {
+        metrics.write_summary(std::io::stderr())?;
+    } else if let Some(stats) = metrics.cache_hits {
+        eprintln!("hot cache: {} hits, {} misses", stats.hits, stats.misses);
+    }
+    if let Some(path) = &args.metrics_output {
+        // Replaced atomically, so that collectors never read half of it
+        let tmp_path = path.with_extension("tmp");
+        metrics.write_prometheus(BufWriter::new(File::create(&tmp_path)?))?;
+        fs::rename(tmp_path, path)?;
+    }
+    write_state_as(
+        state,
+        args.output_format.clone().into(),
+        BufWriter::new(std::io::stdout()),
+    )?;
+
+    Ok(summary)
+}
+
+fn open_inputs(args: &Args) -> Result<MultiReader<TxReader<Box<dyn Read>>>, std::io::Error> {
+    let inputs = args
+        .input_files
+        .iter()
+        .map(|path| {
+            let format = match &args.input_format {
+                Some(format) => format.clone().into(),
+                None => InputFormat::from_extension(path).unwrap_or_default(),
+            };
+            let input: Box<dyn Read> = if path.as_os_str() == "-" {
+                Box::new(std::io::stdin().lock())
+            } else {
+                Box::new(File::open(path)?)
+            };
+            Ok(TxReader::new(format, decompressed(input)?).with_source(path.to_string_lossy()))
+        })
+        .collect::<Result<_, std::io::Error>>()?;
+    Ok(MultiReader::new(inputs).with_merge_by_timestamp(args.merge_by_timestamp))
+}
@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, io::Write, time::Duration};
+
+use crate::cache::lru::HitStats;
+
+/// How a run went, see [`crate::bank::Bank::metrics`]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Metrics {
+    /// Applied transactions by type
+    pub applied: BTreeMap<&'static str, u64>,
+    /// Rejected transactions by type and rejection reason
+    pub rejected: BTreeMap<(&'static str, &'static str), u64>,
+    /// Lookups of transactions by id in the cache whichever backend it is, a miss is an id the cache doesn't hold
+    pub cache_lookups: HitStats,
+    /// Hits and misses of the hot cache, if there's one
+    pub cache_hits: Option<HitStats>,
+    /// Bytes the transaction cache wrote to disk, if it can tell
+    pub cache_bytes_written: Option<u64>,
+    /// Bytes the account store wrote to disk, if it can tell
+    pub account_bytes_written: Option<u64>,
+    /// Time from the first transaction to the last one
+    pub elapsed: Duration,
+}
+
+impl Metrics {
+    pub fn transactions(&self) -> u64 {
+        self.applied.values().chain(self.rejected.values()).sum()
+    }
+
+    /// Transactions per second, applied or not
+    pub fn throughput(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.transactions() as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    /// Human-readable summary, one metric per line
+    pub fn write_summary(&self, mut out: impl Write) -> std::io::Result<()> {
+        for (r#type, count) in &self.applied {
+            writeln!(out, "applied {}: {}", r#type, count)?;
+        }
+        for ((r#type, reason), count) in &self.rejected {
+            writeln!(out, "rejected {} ({}): {}", r#type, reason, count)?;
+        }
+        writeln!(
+            out,
+            "tx cache: {} hits, {} misses",
+            self.cache_lookups.hits, self.cache_lookups.misses
+        )?;
+        if let Some(stats) = self.cache_hits {
+            writeln!(
+                out,
+                "hot cache: {} hits, {} misses",
+                stats.hits, stats.misses
+            )?;
+        }
+        if let Some(bytes) = self.cache_bytes_written {
+            writeln!(out, "tx cache bytes written: {}", bytes)?;
+        }
+        if let Some(bytes) = self.account_bytes_written {
+            writeln!(out, "account store bytes written: {}", bytes)?;
+        }
+        writeln!(
+            out,
+            "{} transactions in {:.3}s, {:.0} tx/s",
+            self.transactions(),
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )
+    }
+
+    /// Prometheus text exposition format, e.g. for node_exporter's textfile collector
+    pub fn write_prometheus(&self, mut out: impl Write) -> std::io::Result<()> {
+        header(
+            &mut out,
+            "nesse_bank_transactions_applied_total",
+            "counter",
+            "Applied transactions by type",
+        )?;
+        for (r#type, count) in &self.applied {
+            writeln!(
+                out,
+                "nesse_bank_transactions_applied_total{{type=\"{}\"}} {}",
+                r#type, count
+            )?;
+        }
+
+        header(
+            &mut out,
+            "nesse_bank_transactions_rejected_total",
+            "counter",
+            "Rejected transactions by type and reason",
+        )?;
+        for ((r#type, reason), count) in &self.rejected {
+            writeln!(
+                out,
+                "nesse_bank_transactions_rejected_total{{type=\"{}\",reason=\"{}\"}} {}",
+                r#type, reason, count
+            )?;
+        }
+
+        header(
+            &mut out,
+            "nesse_bank_tx_cache_lookups_total",
+            "counter",
+            "Transaction cache lookups by result",
+        )?;
+        writeln!(
+            out,
+            "nesse_bank_tx_cache_lookups_total{{result=\"hit\"}} {}",
+            self.cache_lookups.hits
+        )?;
+        writeln!(
+            out,
+            "nesse_bank_tx_cache_lookups_total{{result=\"miss\"}} {}",
+            self.cache_lookups.misses
+        )?;
+        if let Some(stats) = self.cache_hits {
+            header(
+                &mut out,
+                "nesse_bank_hot_cache_lookups_total",
+                "counter",
+                "Hot cache lookups by result",
+            )?;
+            writeln!(
+                out,
+                "nesse_bank_hot_cache_lookups_total{{result=\"hit\"}} {}",
+                stats.hits
+            )?;
+            writeln!(
+                out,
+                "nesse_bank_hot_cache_lookups_total{{result=\"miss\"}} {}",
+                stats.misses
+            )?;
+        }
+
+        let bytes_written = [
+            ("tx-cache", self.cache_bytes_written),
+            ("accounts", self.account_bytes_written),
+        ];
+        if bytes_written.iter().any(|(_, bytes)| bytes.is_some()) {
+            header(
+                &mut out,
+                "nesse_bank_disk_bytes_written_total",
+                "counter",
+                "Bytes written to disk by store",
+            )?;
+            for (store, bytes) in bytes_written {
+                if let Some(bytes) = bytes {
+                    writeln!(
+                        out,
+                        "nesse_bank_disk_bytes_written_total{{store=\"{}\"}} {}",
+                        store, bytes
+                    )?;
+                }
+            }
+        }
+
+        header(
+            &mut out,
+            "nesse_bank_run_duration_seconds",
+            "gauge",
+            "Time from the first transaction to the last one",
+        )?;
+        writeln!(
+            out,
+            "nesse_bank_run_duration_seconds {}",
+            self.elapsed.as_secs_f64()
+        )?;
+        header(
+            &mut out,
+            "nesse_bank_throughput_transactions_per_second",
+            "gauge",
+            "Transactions per second, applied or not",
+        )?;
+        writeln!(
+            out,
+            "nesse_bank_throughput_transactions_per_second {}",
+            self.throughput()
+        )
+    }
+}
+
+fn header(out: &mut impl Write, name: &str, r#type: &str, help: &str) -> std::io::Result<()> {
+    writeln!(out, "# HELP {} {}", name, help)?;
+    writeln!(out, "# TYPE {} {}", name, r#type)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use insta::assert_snapshot;
+
+    use crate::cache::lru::HitStats;
+
+    use super::Metrics;
+
+    #[test]
+    fn prometheus_exposition() {
+        let metrics = Metrics {
+            applied: [("deposit", 3), ("withdrawal", 1)].into_iter().collect(),
+            rejected: [(("dispute", "unknown-tx"), 2)].into_iter().collect(),
+            cache_lookups: HitStats { hits: 2, misses: 4 },
+            cache_hits: Some(HitStats { hits: 5, misses: 1 }),
+            cache_bytes_written: Some(1024),
+            account_bytes_written: None,
+            elapsed: Duration::from_millis(500),
+        };
+        assert_eq!(metrics.throughput(), 12.0);
+
+        let mut out = Vec::new();
+        metrics.write_prometheus(&mut out).unwrap();
+        assert_snapshot!(String::from_utf8(out).unwrap());
+    }
+}
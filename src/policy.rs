@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+use crate::tx::incoming::IncomingTxDetails;
+
+/// Business rules the transaction state machine consults, defaults are the rules the engine started with
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Policy {
+    /// Accept deposits to frozen accounts
+    pub frozen_deposits: bool,
+    /// Accept withdrawals from frozen accounts
+    pub frozen_withdrawals: bool,
+    /// Accept new disputes on frozen accounts, ongoing disputes can always be resolved or charged back
+    pub frozen_disputes: bool,
+    /// Accept disputes of withdrawals, not just deposits
+    pub dispute_withdrawals: bool,
+    /// Accept disputes that leave the available balance negative, e.g. when the deposit has already been withdrawn, the
+    /// total balance stays the same until the chargeback
+    pub dispute_overdraft: bool,
+    /// Accept chargebacks that leave the total balance negative, rejected ones leave the transaction under dispute
+    pub chargeback_overdraft: bool,
+    /// How many times a transaction can be disputed, a resolved transaction can be disputed again until the limit is reached
+    pub max_dispute_cycles: u8,
+    /// How long after a transaction it can still be disputed, transactions a window older than that are forgotten and
+    /// evicted from the cache unless they're under dispute
+    pub dispute_window: Option<DisputeWindow>,
+}
+
+/// Age limit of disputable transactions, e.g. `{"transactions": 1000000}` or `{"seconds": 2592000}`
+///
+/// The kind of window must stay the same across runs resumed from the same state
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeWindow {
+    /// Number of transactions, applied or not, that may come after the disputed one
+    Transactions(u64),
+    /// Seconds between the disputed transaction and the dispute, by the `timestamp` input column
+    Seconds(u64),
+}
+
+impl DisputeWindow {
+    /// Window size in the units of the bank's clock
+    pub fn size(&self) -> u64 {
+        match self {
+            DisputeWindow::Transactions(size) | DisputeWindow::Seconds(size) => *size,
+        }
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            frozen_deposits: false,
+            frozen_withdrawals: false,
+            frozen_disputes: true,
+            dispute_withdrawals: true,
+            dispute_overdraft: true,
+            chargeback_overdraft: true,
+            max_dispute_cycles: 1,
+            dispute_window: None,
+        }
+    }
+}
+
+impl Policy {
+    /// Whether a transaction of this kind can ever be disputed under these rules
+    pub fn is_disputable(&self, details: &IncomingTxDetails) -> bool {
+        self.max_dispute_cycles > 0
+            && match details {
+                IncomingTxDetails::Deposit(_) => true,
+                IncomingTxDetails::Withdrawal(_) => self.dispute_withdrawals,
+                _ => false,
+            }
+    }
+
+    /// Whether a transaction applied at `applied_at` by the bank's clock can't be disputed anymore at `now`
+    pub fn dispute_window_closed(&self, applied_at: u64, now: u64) -> bool {
+        self.dispute_window
+            .is_some_and(|window| now.saturating_sub(applied_at) > window.size())
+    }
+
+    /// Whether a transaction applied at `applied_at` is a whole window past its dispute window at `now`, so that the bank
+    /// treats it the same whether a cache has evicted it already or not
+    pub fn forgets(&self, applied_at: u64, now: u64) -> bool {
+        self.dispute_window
+            .is_some_and(|window| now.saturating_sub(applied_at) > window.size().saturating_mul(2))
+    }
+}
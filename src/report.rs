@@ -0,0 +1,249 @@
+use std::{borrow::Cow, io::Write};
+
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{
+    account::{AccountId, TxRejection},
+    ledger::JournalEntry,
+    tx::{incoming::IncomingTx, TxId},
+    Money,
+};
+
+#[derive(Error, Debug)]
+pub enum ReportError {
+    #[error("error writing CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("error writing JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("can't quarantine records of inputs with different headers into one file")]
+    QuarantineHeader,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    JsonLines,
+}
+
+/// A single row of the rejected transactions report
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct RejectedTx {
+    /// Input the transaction came from, the file path or `-` for stdin
+    pub source: String,
+    pub line: u64,
+    pub r#type: &'static str,
+    pub client: AccountId,
+    pub tx: TxId,
+    pub amount: Option<Money>,
+    pub reason: String,
+}
+
+impl RejectedTx {
+    pub fn new(source: &str, line: u64, tx: &IncomingTx, reason: TxRejection) -> Self {
+        Self {
+            source: source.to_owned(),
+            line,
+            r#type: tx.details.name(),
+            client: tx.account,
+            tx: tx.id,
+            amount: tx.details.amount(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+pub trait RejectionSink {
+    fn rejected(&mut self, rejected: RejectedTx) -> Result<(), ReportError>;
+}
+
+/// A single row of the account lock state changes report
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct LockChange {
+    /// Input the transaction came from, the file path or `-` for stdin
+    pub source: String,
+    pub line: u64,
+    pub r#type: &'static str,
+    pub client: AccountId,
+    pub tx: TxId,
+    pub locked: bool,
+    /// Who issued the `lock` or `unlock`, `None` for chargebacks
+    pub operator: Option<String>,
+    /// Unix timestamp in seconds of the change, if the input has one
+    pub timestamp: Option<u64>,
+}
+
+impl LockChange {
+    pub fn new(source: &str, line: u64, tx: &IncomingTx, locked: bool) -> Self {
+        Self {
+            source: source.to_owned(),
+            line,
+            r#type: tx.details.name(),
+            client: tx.account,
+            tx: tx.id,
+            locked,
+            operator: None,
+            timestamp: None,
+        }
+    }
+
+    pub fn with_operator(self, operator: Option<&str>) -> Self {
+        Self {
+            operator: operator.map(str::to_owned),
+            ..self
+        }
+    }
+
+    pub fn with_timestamp(self, timestamp: Option<u64>) -> Self {
+        Self { timestamp, ..self }
+    }
+}
+
+pub trait AuditSink {
+    fn lock_changed(&mut self, change: LockChange) -> Result<(), ReportError>;
+}
+
+pub trait JournalSink {
+    fn journal(&mut self, entry: JournalEntry) -> Result<(), ReportError>;
+}
+
+/// Report made of rows of a single type, written as CSV or JSON Lines
+pub enum Report<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl<W: Write> Report<W> {
+    pub fn new(format: ReportFormat, output: W) -> Self {
+        match format {
+            ReportFormat::Csv => Self::Csv(Box::new(csv::WriterBuilder::new().from_writer(output))),
+            ReportFormat::JsonLines => Self::JsonLines(output),
+        }
+    }
+
+    pub fn write(&mut self, row: &impl Serialize) -> Result<(), ReportError> {
+        match self {
+            Report::Csv(out) => out.serialize(row)?,
+            Report::JsonLines(out) => {
+                serde_json::to_writer(&mut *out, row)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), ReportError> {
+        match self {
+            Report::Csv(out) => out.flush()?,
+            Report::JsonLines(out) => out.flush()?,
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> RejectionSink for Report<W> {
+    fn rejected(&mut self, rejected: RejectedTx) -> Result<(), ReportError> {
+        self.write(&rejected)
+    }
+}
+
+impl<W: Write> JournalSink for Report<W> {
+    fn journal(&mut self, entry: JournalEntry) -> Result<(), ReportError> {
+        self.write(&entry)
+    }
+}
+
+impl<W: Write> AuditSink for Report<W> {
+    fn lock_changed(&mut self, change: LockChange) -> Result<(), ReportError> {
+        self.write(&change)
+    }
+}
+
+pub trait QuarantineSink {
+    /// `raw` is the record at `line` of `source` as it was in the input, `header` the header of that input if it has one
+    fn quarantine(
+        &mut self,
+        source: &str,
+        line: u64,
+        header: Option<&[u8]>,
+        raw: &[u8],
+    ) -> Result<(), ReportError>;
+}
+
+/// Collects unparseable input records as is under the header of their input, so that they can be fixed and replayed
+/// later
+///
+/// CSV records are prefixed with `source` and `line` columns, which are ignored when they're read back. JSON Lines
+/// records are kept without them, there's no adding a field without rewriting the record
+pub struct QuarantineReport<W: Write> {
+    out: W,
+    /// Header the records written so far came with, `None` until the first one
+    header: Option<Option<Vec<u8>>>,
+}
+
+impl<W: Write> QuarantineReport<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            out: output,
+            header: None,
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), ReportError> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<(), ReportError> {
+        self.out.write_all(line)?;
+        // The last line of the input may have no terminator
+        if !line.ends_with(b"\n") {
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> QuarantineSink for QuarantineReport<W> {
+    fn quarantine(
+        &mut self,
+        source: &str,
+        line: u64,
+        header: Option<&[u8]>,
+        raw: &[u8],
+    ) -> Result<(), ReportError> {
+        // Records that couldn't be read at all have nothing to keep
+        if raw.is_empty() {
+            return Ok(());
+        }
+        let header = header.map(<[u8]>::trim_ascii_end);
+        match &self.header {
+            None => {
+                if let Some(header) = header {
+                    self.out.write_all(b"source,line,")?;
+                    self.write_line(header)?;
+                }
+                self.header = Some(header.map(<[u8]>::to_vec));
+            }
+            Some(written) if written.as_deref() != header => {
+                return Err(ReportError::QuarantineHeader)
+            }
+            Some(_) => {}
+        }
+        if header.is_some() {
+            write!(self.out, "{},{},", csv_field(source), line)?;
+        }
+        self.write_line(raw)
+    }
+}
+
+/// Quotes the field if it would break the record otherwise
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
@@ -1,12 +1,33 @@
 use std::{
     fs::File,
+    io::{Read, Write},
+    num::NonZeroUsize,
     path::{Path, PathBuf},
 };
 
+use flate2::{write::GzEncoder, Compression};
 use insta::{assert_snapshot, glob};
 use itertools::Itertools;
+use tempdir::TempDir;
 
-use crate::{io::csv_reader, tx::incoming::IncomingTx, util::write_state, Money};
+use crate::{
+    account::AccountId,
+    bank::{
+        begin_run, AccountStore, Bank, CacheError, InMemoryAccountStore, InMemoryTxCache,
+        StateError, TxCache,
+    },
+    cache::{compact::CompactTxCache, lru::HitStats, sqlite::SqliteTxCache},
+    io::{csv_reader, decompressed, jsonl_reader, InputFormat, MultiReader, TxReader, TxSource},
+    ledger::{read_journal, rebuild_accounts},
+    policy::{DisputeWindow, Policy},
+    report::{QuarantineReport, Report, ReportFormat},
+    tx::{incoming::IncomingTx, stored::TxDetails, TxId},
+    util::{
+        disk_account_store, disk_cache, historic_run, historic_run_from, historic_run_with,
+        write_state, write_state_as, HistoricRunError, OnParseError, OutputFormat, RunOptions,
+    },
+    Money,
+};
 
 #[test]
 fn historic_runs() {
@@ -15,6 +36,72 @@ fn historic_runs() {
     });
 }
 
+#[test]
+fn compact_cache_matches_in_memory_cache() {
+    glob!("test-data/historic-runs/*.csv", |path| {
+        let cache = CompactTxCache::default().with_disputable_amounts_only(Policy::default());
+        let state = historic_run(File::open(path).unwrap(), Box::new(cache)).unwrap();
+        let mut buf = Vec::new();
+        write_state(state, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            historic_run_small(path),
+            "{:?}",
+            path
+        );
+    });
+}
+
+#[test]
+fn journal_rebuilds_balances() {
+    glob!("test-data/historic-runs/*.csv", |path| {
+        let mut journal = Report::new(ReportFormat::Csv, Vec::new());
+        let (state, _) = historic_run_with(
+            File::open(path).unwrap(),
+            Bank::default(),
+            RunOptions {
+                journal: Some(&mut journal),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let buf = report_output(journal);
+
+        let rebuilt = rebuild_accounts(
+            read_journal(buf.as_slice())
+                .map(Result::unwrap)
+                .collect_vec(),
+        );
+
+        let mut state = state;
+        for account in state.accounts().unwrap() {
+            let (id, account) = account.unwrap();
+            let (balance, held) = rebuilt
+                .get(&id)
+                .map(|a| (a.balance, a.held))
+                .unwrap_or_default();
+            assert_eq!(balance, account.balance, "client {} in {:?}", id, path);
+            assert_eq!(held, account.held, "client {} in {:?}", id, path);
+        }
+    });
+}
+
+#[test]
+fn journal_entries() {
+    let mut journal = Report::new(ReportFormat::Csv, Vec::new());
+    historic_run_with(
+        File::open("src/test-data/historic-runs/chargeback-allow-overdraft.csv").unwrap(),
+        Bank::default(),
+        RunOptions {
+            journal: Some(&mut journal),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let buf = report_output(journal);
+    assert_snapshot!(String::from_utf8(buf).unwrap());
+}
+
 fn historic_run_small(path: impl AsRef<Path>) -> String {
     let state = crate::util::historic_run_small(File::open(path).unwrap()).unwrap();
     let mut buf = Vec::new();
@@ -30,6 +117,694 @@ fn historic_run_large(path: impl AsRef<Path>) -> String {
     String::from_utf8(buf).unwrap()
 }
 
+fn report_output(report: Report<Vec<u8>>) -> Vec<u8> {
+    match report {
+        Report::Csv(out) => out.into_inner().unwrap(),
+        Report::JsonLines(out) => out,
+    }
+}
+
+fn rejected_report(input: &str, format: ReportFormat) -> String {
+    let mut report = Report::new(format, Vec::new());
+    historic_run_with(
+        input.as_bytes(),
+        Bank::default(),
+        RunOptions {
+            rejected: Some(&mut report),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    report.flush().unwrap();
+    let buf = report_output(report);
+    String::from_utf8(buf).unwrap()
+}
+
+const INPUT_WITH_REJECTIONS: &str = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 2.0
+deposit, 1, 1, 5.0
+resolve, 1, 1,
+dispute, 1, 3,
+dispute, 1, 1,
+chargeback, 1, 1,
+deposit, 1, 4, 1.0
+"#;
+
+#[test]
+fn rejected_report_csv() {
+    assert_snapshot!(rejected_report(INPUT_WITH_REJECTIONS, ReportFormat::Csv));
+}
+
+#[test]
+fn rejected_report_jsonl() {
+    assert_snapshot!(rejected_report(
+        INPUT_WITH_REJECTIONS,
+        ReportFormat::JsonLines
+    ));
+}
+
+#[test]
+fn metrics_count_transactions_by_type_and_reason() {
+    let (state, _) = historic_run_with(
+        INPUT_WITH_REJECTIONS.as_bytes(),
+        Bank::default(),
+        RunOptions::default(),
+    )
+    .unwrap();
+    let metrics = state.metrics();
+
+    assert_eq!(metrics.transactions(), 8);
+    assert_eq!(
+        metrics.applied.into_iter().collect_vec(),
+        [("chargeback", 1), ("deposit", 1), ("dispute", 1)]
+    );
+    assert_eq!(
+        metrics.rejected.into_iter().collect_vec(),
+        [
+            (("deposit", "account-frozen"), 1),
+            (("deposit", "duplicate-tx"), 1),
+            (("dispute", "unknown-tx"), 1),
+            (("resolve", "wrong-tx-state"), 1),
+            (("withdrawal", "insufficient-funds"), 1),
+        ]
+    );
+    assert_eq!(metrics.cache_lookups, HitStats { hits: 4, misses: 4 });
+    assert_eq!(metrics.cache_hits, None);
+}
+
+#[test]
+fn state_output_formats() {
+    let input = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 20, 2, 1234.5678
+        dispute, 20, 2,
+        deposit, 3, 3, 10.0
+        dispute, 3, 3,
+        chargeback, 3, 3,
+        ";
+    for (name, format) in [
+        ("csv", OutputFormat::Csv),
+        ("json", OutputFormat::Json),
+        ("jsonl", OutputFormat::JsonLines),
+        ("table", OutputFormat::Table),
+    ] {
+        let state = crate::util::historic_run_small(input.as_bytes()).unwrap();
+        let mut buf = Vec::new();
+        write_state_as(state, format, &mut buf).unwrap();
+        assert_snapshot!(
+            format!("state_output_{}", name),
+            String::from_utf8(buf).unwrap()
+        );
+    }
+}
+
+#[test]
+fn audit_report_records_lock_changes() {
+    let mut audit = Report::new(ReportFormat::Csv, Vec::new());
+    historic_run_with(
+        File::open("src/test-data/historic-runs/unlock.csv").unwrap(),
+        Bank::default(),
+        RunOptions {
+            audit: Some(&mut audit),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let buf = report_output(audit);
+    assert_snapshot!(String::from_utf8(buf).unwrap());
+}
+
+#[test]
+fn admin_operations_need_an_operator_and_a_timestamp() {
+    let input = "type, client, tx, timestamp, operator
+lock, 1, 1, , alice
+lock, 1, 2, 1700000000,
+lock, 1, 3, 1700000000, alice
+";
+    let errors = csv_reader(input.as_bytes())
+        .map(|record| record.err().map(|e| e.kind.to_string()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        errors,
+        [
+            Some("missing field `timestamp`".to_owned()),
+            Some("missing field `operator`".to_owned()),
+            None
+        ]
+    );
+}
+
+const INPUT_WITH_MALFORMED_ROWS: &str = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, one, 2, 1.0
+transfer, 1, 3, 1.0
+deposit, 1, 4, -1.0
+deposit, 1, 5, 2.0
+"#;
+
+#[test]
+fn malformed_rows_abort_by_default() {
+    assert!(crate::util::historic_run_small(INPUT_WITH_MALFORMED_ROWS.as_bytes()).is_err());
+}
+
+#[test]
+fn malformed_rows_are_skipped() {
+    let (state, summary) = historic_run_with(
+        INPUT_WITH_MALFORMED_ROWS.as_bytes(),
+        Bank::default(),
+        RunOptions {
+            on_parse_error: OnParseError::Skip,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(summary.skipped, 3);
+    let mut buf = Vec::new();
+    write_state(state, &mut buf).unwrap();
+    assert_snapshot!(String::from_utf8(buf).unwrap());
+}
+
+#[test]
+fn malformed_rows_are_quarantined() {
+    let mut buf = Vec::new();
+    let mut quarantine = QuarantineReport::new(&mut buf);
+    let (_, summary) = historic_run_with(
+        INPUT_WITH_MALFORMED_ROWS.as_bytes(),
+        Bank::default(),
+        RunOptions {
+            on_parse_error: OnParseError::Quarantine(&mut quarantine),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    quarantine.flush().unwrap();
+    drop(quarantine);
+    assert_eq!(summary.skipped, 3);
+    assert_snapshot!(String::from_utf8(buf).unwrap());
+}
+
+#[test]
+fn quarantined_rows_can_be_replayed() {
+    let mut input = b"Client,Type,Amount,Tx\n1,deposit,1.0,1\n1, deposit ,\"2.0\",x\n1,".to_vec();
+    input.extend_from_slice(b"dep\xffosit,1.0,3\r\n1,deposit,1.0,4");
+    let mut buf = Vec::new();
+    let mut quarantine = QuarantineReport::new(&mut buf);
+    historic_run_from(
+        TxReader::new(InputFormat::Csv, input.as_slice()).with_source("march,1.csv"),
+        Bank::default(),
+        RunOptions {
+            on_parse_error: OnParseError::Quarantine(&mut quarantine),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    quarantine.flush().unwrap();
+    drop(quarantine);
+
+    // Header first, then the records byte for byte after where they came from, even the one that isn't valid UTF-8
+    let mut expected =
+        b"source,line,Client,Type,Amount,Tx\n\"march,1.csv\",3,1, deposit ,\"2.0\",x\n".to_vec();
+    expected.extend_from_slice(b"\"march,1.csv\",4,1,dep\xffosit,1.0,3\r\n");
+    assert_eq!(buf, expected);
+    let replayed = csv_reader(buf.as_slice()).collect::<Vec<_>>();
+    assert_eq!(replayed.len(), 2);
+    assert!(replayed.iter().all(Result::is_err));
+}
+
+#[test]
+fn parse_error_points_at_the_record() {
+    let input = r#"type, client, tx, amount
+            deposit, 1, 1, 1.0
+            deposit, 1, 2
+            "#
+    .as_bytes();
+
+    let error = csv_reader(input).nth(1).unwrap().unwrap_err();
+
+    assert_eq!(error.position.as_ref().map(|p| p.line()), Some(3));
+    assert_eq!(
+        error.to_string(),
+        "missing field `amount` at line 3, byte 56: `deposit,1,2`"
+    );
+}
+
+#[test]
+fn columns_are_resolved_by_header_name() {
+    let input = r#"tx_id, Type, note, client_id, amount
+            1, deposit, first, 1, 1.0
+            2, withdrawal, , 1, 0.5
+            1, dispute, disputed, 1,
+            "#
+    .as_bytes();
+
+    let records = csv_reader(input).map(Result::unwrap).collect_vec();
+
+    assert_eq!(
+        records,
+        vec![
+            IncomingTx::deposit(1, 1, "1.0").unwrap(),
+            IncomingTx::withdrawal(2, 1, "0.5").unwrap(),
+            IncomingTx::dispute(1, 1),
+        ]
+    );
+}
+
+#[test]
+fn missing_column_stops_the_run() {
+    let input = r#"type, tx, amount
+            deposit, 1, 1.0
+            "#;
+
+    let error = csv_reader(input.as_bytes()).next().unwrap().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "missing column `client` in the header at line 1, byte 0: `type,tx,amount`"
+    );
+    assert!(!error.is_recoverable());
+    assert_eq!(csv_reader(input.as_bytes()).count(), 1);
+
+    let result = historic_run_with(
+        input.as_bytes(),
+        Bank::default(),
+        RunOptions {
+            on_parse_error: OnParseError::Skip,
+            ..Default::default()
+        },
+    );
+    assert!(matches!(result, Err(HistoricRunError::ParseError(_))));
+}
+
+fn multiple_inputs(inputs: [&str; 2], merge_by_timestamp: bool) -> String {
+    let inputs = inputs
+        .into_iter()
+        .zip(["a.csv", "b.csv"])
+        .map(|(input, source)| {
+            TxReader::new(InputFormat::Csv, input.as_bytes()).with_source(source)
+        })
+        .collect();
+    let records = MultiReader::new(inputs).with_merge_by_timestamp(merge_by_timestamp);
+    let mut report = Report::new(ReportFormat::Csv, Vec::new());
+    let (state, _) = historic_run_from(
+        records,
+        Bank::default(),
+        RunOptions {
+            rejected: Some(&mut report),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let mut buf = report_output(report);
+    write_state(state, &mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+const MULTIPLE_INPUTS: [&str; 2] = [
+    r#"type, client, tx, amount, timestamp
+withdrawal, 1, 2, 1.0, 200
+deposit, 1, 4, 1.0, 400
+"#,
+    r#"type, client, tx, amount, timestamp
+deposit, 1, 1, 2.0, 100
+dispute, 1, 1, , 300
+"#,
+];
+
+#[test]
+fn multiple_inputs_are_read_in_order() {
+    assert_snapshot!(multiple_inputs(MULTIPLE_INPUTS, false));
+}
+
+#[test]
+fn multiple_inputs_are_merged_by_timestamp() {
+    assert_snapshot!(multiple_inputs(MULTIPLE_INPUTS, true));
+}
+
+#[test]
+fn inputs_out_of_order_are_not_merged() {
+    let merge = |inputs: [&str; 2]| {
+        let inputs = inputs
+            .into_iter()
+            .zip(["a.csv", "b.csv"])
+            .map(|(input, source)| csv_reader(input.as_bytes()).with_source(source))
+            .collect();
+        MultiReader::new(inputs)
+            .with_merge_by_timestamp(true)
+            .find_map(Result::err)
+            .map(|e| e.to_string())
+    };
+    let ordered = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, 10\n";
+
+    assert_eq!(
+        merge([
+            ordered,
+            "type, client, tx, amount, timestamp\ndeposit, 1, 2, 1.0, 20\ndeposit, 1, 3, 1.0, 15\n"
+        ]),
+        Some("can't merge by timestamp: timestamp 15 on line 3 of b.csv is earlier than 20 before it".into())
+    );
+    assert_eq!(
+        merge([ordered, "type, client, tx, amount\ndeposit, 1, 2, 1.0\n"]),
+        Some("can't merge by timestamp: b.csv has no `timestamp` column".into())
+    );
+    assert_eq!(
+        merge([
+            ordered,
+            "type, client, tx, amount, timestamp\ndeposit, 1, 2, 1.0,\ndeposit, 1, 3, 1.0, 20\n"
+        ]),
+        Some(
+            "can't merge by timestamp: line 2 of b.csv has no timestamp and no record before it does"
+                .into()
+        )
+    );
+}
+
+#[test]
+fn merged_records_point_at_their_input() {
+    let inputs = [
+        "type, client, tx, amount, timestamp\ndeposit, 1, 2, 1.0, 20\ndispute, 1, 2, ,\n",
+        "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, 10\ndeposit, 1, 3, 1.0, 20\n",
+    ]
+    .into_iter()
+    .map(|input| csv_reader(input.as_bytes()))
+    .collect();
+    let mut records = MultiReader::new(inputs).with_merge_by_timestamp(true);
+
+    let mut read = Vec::new();
+    while let Some(tx) = records.next() {
+        read.push((
+            tx.unwrap().id.0,
+            records.input(),
+            records.line(),
+            records.timestamp(),
+        ));
+    }
+
+    // The dispute has no timestamp of its own and stays right after the deposit of its input
+    assert_eq!(
+        read,
+        [
+            (1, 1, 2, Some(10)),
+            (2, 0, 2, Some(20)),
+            (2, 0, 3, None),
+            (3, 1, 3, Some(20)),
+        ]
+    );
+}
+
+fn resume_from_saved_state_with(accounts: impl Fn(&Path) -> Box<dyn AccountStore>) -> String {
+    let first_day = r#"type, client, tx, amount
+deposit, 1, 1, 2.0
+deposit, 2, 2, 1.0
+deposit, 1, 3, 1.0
+"#;
+    let second_day = r#"type, client, tx, amount
+dispute, 1, 1,
+withdrawal, 2, 4, 0.5
+deposit, 1, 3, 5.0
+chargeback, 1, 1,
+"#;
+    let state_dir = TempDir::new("nesse-bank-state").unwrap();
+    let open = || {
+        Bank::open(
+            Box::new(disk_cache(&state_dir.path().join("tx-cache")).unwrap()),
+            accounts(state_dir.path()),
+            state_dir.path(),
+        )
+        .unwrap()
+    };
+
+    let (mut state, _) =
+        historic_run_with(first_day.as_bytes(), open(), RunOptions::default()).unwrap();
+    state.save(state_dir.path()).unwrap();
+    drop(state);
+
+    let (state, _) =
+        historic_run_with(second_day.as_bytes(), open(), RunOptions::default()).unwrap();
+    let mut buf = Vec::new();
+    write_state(state, &mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+#[test]
+fn failed_run_is_rolled_back() {
+    let state_dir = TempDir::new("nesse-bank-state").unwrap();
+    let dir = state_dir.path();
+    let stores = [dir.join("tx-cache"), dir.join("accounts")];
+    let run = |input: &str| {
+        begin_run(dir, &stores).unwrap();
+        // Both stores write every change right away
+        let bank = Bank::open(
+            disk_cache(&stores[0])
+                .unwrap()
+                .with_batch_size(NonZeroUsize::new(1).unwrap()),
+            disk_account_store(&stores[1])
+                .unwrap()
+                .with_batch_size(NonZeroUsize::new(1).unwrap()),
+            dir,
+        )
+        .unwrap();
+        let (mut state, _) = historic_run_with(input.as_bytes(), bank, RunOptions::default())?;
+        state.save(dir).unwrap();
+        Ok::<_, HistoricRunError>(state.account(AccountId(1)).unwrap().unwrap().balance)
+    };
+
+    assert_eq!(
+        run("type, client, tx, amount\ndeposit, 1, 1, 2.0\n").unwrap(),
+        2.into()
+    );
+    assert!(run("type, client, tx, amount\ndeposit, 1, 2, 1.0\ndeposit, 1, x, 1.0\n").is_err());
+    // Neither credited nor taken for a duplicate of its fixed version
+    assert_eq!(
+        run("type, client, tx, amount\ndeposit, 1, 2, 0.5\n").unwrap(),
+        "2.5".parse().unwrap()
+    );
+}
+
+#[test]
+fn state_is_resumed_with_the_same_account_store() {
+    let state_dir = TempDir::new("nesse-bank-state").unwrap();
+    let cache = || disk_cache(&state_dir.path().join("tx-cache")).unwrap();
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 2.0\n";
+    let bank = Bank::open(
+        cache(),
+        disk_account_store(&state_dir.path().join("accounts")).unwrap(),
+        state_dir.path(),
+    )
+    .unwrap();
+    let (mut state, _) = historic_run_with(input.as_bytes(), bank, RunOptions::default()).unwrap();
+    state.save(state_dir.path()).unwrap();
+    drop(state);
+
+    assert!(matches!(
+        Bank::open(
+            cache(),
+            InMemoryAccountStore::open(state_dir.path()).unwrap(),
+            state_dir.path(),
+        ),
+        Err(StateError::AccountStoreMismatch { .. })
+    ));
+}
+
+#[test]
+fn resume_from_saved_state() {
+    assert_snapshot!(resume_from_saved_state_with(|dir| Box::new(
+        InMemoryAccountStore::open(dir).unwrap()
+    )));
+}
+
+#[test]
+fn accounts_on_disk_match_accounts_in_memory() {
+    assert_eq!(
+        resume_from_saved_state_with(|dir| Box::new(
+            disk_account_store(&dir.join("accounts"))
+                .unwrap()
+                .with_batch_size(NonZeroUsize::new(2).unwrap())
+        )),
+        resume_from_saved_state_with(|dir| Box::new(InMemoryAccountStore::open(dir).unwrap())),
+    );
+}
+
+fn rejected_report_with_policy(input: &str, policy: Policy) -> String {
+    let mut report = Report::new(ReportFormat::Csv, Vec::new());
+    historic_run_with(
+        input.as_bytes(),
+        Bank::default().with_policy(policy),
+        RunOptions {
+            rejected: Some(&mut report),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    String::from_utf8(report_output(report)).unwrap()
+}
+
+#[test]
+fn disputes_after_a_window_in_transactions_are_rejected() {
+    // Disputes of tx 1 to 4 come a window late and are rejected as such, no matter that the sweep every 3 transactions
+    // evicts tx 1 and 2 right before the last two disputes. By then tx 1 and 3 are two windows old and forgotten alike,
+    // whether evicted or not, and their ids are still taken
+    let input = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 1, 4, 1.0
+dispute, 1, 1,
+dispute, 1, 2,
+dispute, 1, 3,
+deposit, 1, 5, 1.0
+dispute, 1, 4,
+dispute, 1, 1,
+dispute, 1, 3,
+deposit, 1, 1, 1.0
+deposit, 1, 3, 1.0
+"#;
+    let policy = Policy {
+        dispute_window: Some(DisputeWindow::Transactions(3)),
+        ..Default::default()
+    };
+    assert_snapshot!(rejected_report_with_policy(input, policy));
+}
+
+#[test]
+fn evicted_transactions_are_remembered_across_runs() {
+    let state_dir = TempDir::new("nesse-bank-state").unwrap();
+    let dir = state_dir.path();
+    let policy = Policy {
+        dispute_window: Some(DisputeWindow::Transactions(1)),
+        ..Default::default()
+    };
+    let run = |input: &str| {
+        let bank = Bank::open(
+            disk_cache(&dir.join("tx-cache")).unwrap(),
+            InMemoryAccountStore::open(dir).unwrap(),
+            dir,
+        )
+        .unwrap()
+        .with_policy(policy);
+        let mut report = Report::new(ReportFormat::Csv, Vec::new());
+        let (mut state, _) = historic_run_with(
+            input.as_bytes(),
+            bank,
+            RunOptions {
+                rejected: Some(&mut report),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        state.save(dir).unwrap();
+        String::from_utf8(report_output(report)).unwrap()
+    };
+
+    run("type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0\ndeposit, 1, 3, 1.0\n");
+    assert_eq!(
+        run("type, client, tx, amount\ndispute, 1, 1,\ndeposit, 1, 1, 1.0\n"),
+        "source,line,type,client,tx,amount,reason\n\
+         -,2,dispute,1,1,,dispute window of the referenced transaction is closed\n\
+         -,3,deposit,1,1,1.0,duplicate transaction id\n"
+    );
+}
+
+#[test]
+fn disputed_transactions_outlive_the_window() {
+    let input = r#"type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 1, 1,
+deposit, 1, 2, 1.0
+deposit, 1, 3, 1.0
+deposit, 1, 4, 1.0
+resolve, 1, 1,
+"#;
+    let policy = Policy {
+        dispute_window: Some(DisputeWindow::Transactions(2)),
+        ..Default::default()
+    };
+    let temp_dir = TempDir::new("nesse-bank").unwrap();
+    let caches: Vec<Box<dyn TxCache>> = vec![
+        Box::new(InMemoryTxCache::default()),
+        Box::new(
+            disk_cache(temp_dir.path())
+                .unwrap()
+                .with_batch_size(NonZeroUsize::new(1).unwrap()),
+        ),
+        Box::new(SqliteTxCache::in_memory().unwrap()),
+    ];
+    for cache in caches {
+        let (state, _) = historic_run_with(
+            input.as_bytes(),
+            Bank::with_cache(cache).with_policy(policy),
+            RunOptions::default(),
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        write_state(state, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,8.0,0.0,8.0,false\n"
+        );
+    }
+}
+
+#[test]
+fn disputes_after_a_window_in_seconds_are_rejected() {
+    let input = r#"type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 500
+deposit, 1, 2, 1.0, 1200
+dispute, 1, 1, , 1600
+dispute, 1, 2, , 1700
+"#;
+    let policy = Policy {
+        dispute_window: Some(DisputeWindow::Seconds(1000)),
+        ..Default::default()
+    };
+    assert_snapshot!(rejected_report_with_policy(input, policy));
+}
+
+/// Fails to store anything past the first `capacity` transactions
+struct FullTxCache {
+    inner: InMemoryTxCache,
+    capacity: usize,
+}
+
+impl TxCache for FullTxCache {
+    fn get_by_id(&self, id: TxId) -> Result<Option<TxDetails>, CacheError> {
+        self.inner.get_by_id(id)
+    }
+
+    fn store(&mut self, tx: TxDetails) -> Result<(), CacheError> {
+        if self.capacity == 0 {
+            return Err(CacheError::Storage(kv::Error::Message("disk full".into())));
+        }
+        self.capacity -= 1;
+        self.inner.store(tx)
+    }
+}
+
+#[test]
+fn cache_errors_stop_the_run() {
+    let input = r#"type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 1.0
+"#;
+    let mut bank = Bank::with_cache(Box::new(FullTxCache {
+        inner: InMemoryTxCache::default(),
+        capacity: 1,
+    }));
+    let result = historic_run_with(input.as_bytes(), bank, RunOptions::default());
+    assert!(matches!(result, Err(HistoricRunError::Cache(_))));
+
+    bank = Bank::with_cache(Box::new(FullTxCache {
+        inner: InMemoryTxCache::default(),
+        capacity: 0,
+    }));
+    assert!(bank
+        .apply_tx(IncomingTx::deposit(1, 1, "1.0").unwrap())
+        .is_err());
+    assert_eq!(
+        bank.account(AccountId(1)).unwrap().unwrap().balance,
+        Money::ZERO
+    );
+}
+
 #[test]
 fn read_sample_input_with_newline() {
     let input = r#"type, client, tx, amount
@@ -79,6 +854,150 @@ fn read_sample_input_without_newline() {
     );
 }
 
+#[test]
+fn compressed_input_matches_plain_input() {
+    glob!("test-data/historic-runs/*.csv", |path| {
+        let plain = std::fs::read(path).unwrap();
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&plain).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(plain.as_slice(), 0).unwrap();
+
+        for compressed in [gzip, zstd] {
+            let state = historic_run(compressed.as_slice(), InMemoryTxCache::default()).unwrap();
+            let mut buf = Vec::new();
+            write_state(state, &mut buf).unwrap();
+            assert_eq!(
+                String::from_utf8(buf).unwrap(),
+                historic_run_small(path),
+                "{:?}",
+                path
+            );
+        }
+    });
+}
+
+#[test]
+fn short_input_passes_through_decompression() {
+    for input in ["", "t", "type"] {
+        let mut buf = String::new();
+        decompressed(input.as_bytes())
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, input);
+    }
+    assert_eq!(
+        InputFormat::from_extension(Path::new("2022-03-01.jsonl.gz")),
+        Some(InputFormat::JsonLines)
+    );
+}
+
+#[test]
+fn read_jsonl_input() {
+    let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1.0"}
+        {"type": "withdrawal", "client": 1, "tx": 2, "amount": 0.5, "timestamp": 1646092800}
+
+        {"type": "dispute", "client": 1, "tx": 1}
+        {"type": "deposit", "client": 1, "tx": 3, "amount": 12345678901234567.89}
+        "#
+    .as_bytes();
+
+    let mut records = jsonl_reader(input);
+
+    assert_eq!(
+        records.next().unwrap().unwrap(),
+        IncomingTx::deposit(1, 1, "1.0").unwrap()
+    );
+    assert_eq!(records.timestamp(), None);
+    assert_eq!(
+        records.next().unwrap().unwrap(),
+        IncomingTx::withdrawal(2, 1, "0.5").unwrap()
+    );
+    assert_eq!(records.timestamp(), Some(1646092800));
+    assert_eq!(records.next().unwrap().unwrap(), IncomingTx::dispute(1, 1));
+    assert_eq!(records.line(), 4);
+    // Beyond what `f64` holds exactly
+    assert_eq!(
+        records.next().unwrap().unwrap(),
+        IncomingTx::deposit(3, 1, "12345678901234567.89").unwrap()
+    );
+    assert!(records.next().is_none());
+}
+
+#[test]
+fn jsonl_input_is_validated_like_csv() {
+    let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "-1.0"}
+        {"type": "withdrawal", "client": 1, "tx": 2}
+        {"type": "deposit", "client": 1, "tx": "3", "amount": "1.0"}
+        {"type": "refund", "client": 1, "tx": 4}
+        "#
+    .as_bytes();
+
+    let errors = jsonl_reader(input)
+        .map(|record| record.unwrap_err().to_string())
+        .collect_vec();
+
+    assert_eq!(
+        errors,
+        [
+            r#"transaction error: negative amount at line 1, byte 0: `{"type": "deposit", "client": 1, "tx": 1, "amount": "-1.0"}`"#,
+            r#"missing field `amount` at line 2, byte 60: `{"type": "withdrawal", "client": 1, "tx": 2}`"#,
+            r#"error parsing JSON at line 3, byte 113: `{"type": "deposit", "client": 1, "tx": "3", "amount": "1.0"}`"#,
+            r#"unknown transaction type `refund` at line 4, byte 182: `{"type": "refund", "client": 1, "tx": 4}`"#,
+        ]
+    );
+}
+
+#[test]
+fn jsonl_input_matches_csv_input() {
+    glob!("test-data/historic-runs/*.csv", |path| {
+        let mut jsonl = Vec::new();
+        let mut csv = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_path(path)
+            .unwrap();
+        for record in csv.records() {
+            let record = record.unwrap();
+            let mut fields = serde_json::json!({
+                "type": record[0],
+                "client": record[1].parse::<u16>().unwrap(),
+                "tx": record[2].parse::<u32>().unwrap(),
+            });
+            if let Some(amount) = record.get(3).filter(|amount| !amount.is_empty()) {
+                fields["amount"] = amount.into();
+            }
+            if let Some(timestamp) = record.get(4).filter(|timestamp| !timestamp.is_empty()) {
+                fields["timestamp"] = timestamp.parse::<u64>().unwrap().into();
+            }
+            if let Some(operator) = record.get(5).filter(|operator| !operator.is_empty()) {
+                fields["operator"] = operator.into();
+            }
+            serde_json::to_writer(&mut jsonl, &fields).unwrap();
+            jsonl.push(b'\n');
+        }
+
+        let (state, _) = historic_run_with(
+            jsonl.as_slice(),
+            Bank::default(),
+            RunOptions {
+                input_format: InputFormat::JsonLines,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        write_state(state, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            historic_run_small(path),
+            "{:?}",
+            path
+        );
+    });
+}
+
 #[ignore = "requires 2.6GiB of disk space and runs for tens of seconds with --release"]
 #[test]
 fn handle_10mil_transactions() {
@@ -86,11 +1005,11 @@ fn handle_10mil_transactions() {
 
     {
         let mut out = csv::WriterBuilder::new().from_writer(File::create(&tx_path).unwrap());
-        out.write_record(&["type", "client", "tx", "amount"])
+        out.write_record(["type", "client", "tx", "amount"])
             .unwrap();
 
         for i in 0..10_000_000 {
-            out.write_record(&[
+            out.write_record([
                 "deposit",
                 "1",
                 i.to_string().as_str(),
@@ -0,0 +1,62 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::TxId;
+
+/// Ids per page, a page takes 8KB once any of them is in the set
+const PAGE_BITS: u32 = 1 << 16;
+
+/// Set of transaction ids at a bit per id, in pages of consecutive ids so that ids far apart don't take up the memory
+/// of the ones in between
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TxIdSet {
+    pages: BTreeMap<u32, Vec<u64>>,
+}
+
+impl TxIdSet {
+    pub fn insert(&mut self, id: TxId) {
+        let (page, word, bit) = Self::position(id);
+        self.pages
+            .entry(page)
+            .or_insert_with(|| vec![0; (PAGE_BITS / 64) as usize])[word] |= bit;
+    }
+
+    pub fn contains(&self, id: TxId) -> bool {
+        let (page, word, bit) = Self::position(id);
+        self.pages
+            .get(&page)
+            .is_some_and(|page| page[word] & bit != 0)
+    }
+
+    fn position(TxId(id): TxId) -> (u32, usize, u64) {
+        (
+            id / PAGE_BITS,
+            (id % PAGE_BITS / 64) as usize,
+            1 << (id % 64),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tx::TxId;
+
+    use super::TxIdSet;
+
+    #[test]
+    fn inserted_ids_are_contained() {
+        let mut ids = TxIdSet::default();
+        for id in [0, 63, 64, 70_000, u32::MAX] {
+            ids.insert(TxId(id));
+        }
+
+        for id in [0, 63, 64, 70_000, u32::MAX] {
+            assert!(ids.contains(TxId(id)), "{}", id);
+        }
+        for id in [1, 65, 65_536, u32::MAX - 1] {
+            assert!(!ids.contains(TxId(id)), "{}", id);
+        }
+        assert_eq!(ids.pages.len(), 3);
+    }
+}
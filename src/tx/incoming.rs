@@ -13,17 +13,19 @@ pub enum IncomingTxError {
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[repr(C)]
 pub enum IncomingTxDetails {
     Deposit(Money),
     Withdrawal(Money),
     Dispute,
     Resolve,
     Chargeback,
+    /// Administrative freeze of the account
+    Lock,
+    /// Administrative unfreeze of the account
+    Unlock,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[repr(C)]
 pub struct IncomingTx {
     pub id: TxId,
     pub account: AccountId,
@@ -31,6 +33,27 @@ pub struct IncomingTx {
 }
 
 impl IncomingTxDetails {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IncomingTxDetails::Deposit(_) => "deposit",
+            IncomingTxDetails::Withdrawal(_) => "withdrawal",
+            IncomingTxDetails::Dispute => "dispute",
+            IncomingTxDetails::Resolve => "resolve",
+            IncomingTxDetails::Chargeback => "chargeback",
+            IncomingTxDetails::Lock => "lock",
+            IncomingTxDetails::Unlock => "unlock",
+        }
+    }
+
+    pub fn amount(&self) -> Option<Money> {
+        match self {
+            IncomingTxDetails::Deposit(amount) | IncomingTxDetails::Withdrawal(amount) => {
+                Some(*amount)
+            }
+            _ => None,
+        }
+    }
+
     pub fn balance_effect(&self) -> Option<Money> {
         match self {
             IncomingTxDetails::Deposit(amount) => Some(*amount),
@@ -90,6 +113,22 @@ impl IncomingTx {
             details: IncomingTxDetails::Chargeback,
         }
     }
+
+    pub fn lock(id: impl Into<TxId>, account: impl Into<AccountId>) -> Self {
+        Self {
+            id: id.into(),
+            account: account.into(),
+            details: IncomingTxDetails::Lock,
+        }
+    }
+
+    pub fn unlock(id: impl Into<TxId>, account: impl Into<AccountId>) -> Self {
+        Self {
+            id: id.into(),
+            account: account.into(),
+            details: IncomingTxDetails::Unlock,
+        }
+    }
 }
 
 trait MoneyExt: Sized {
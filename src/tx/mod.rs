@@ -1,6 +1,7 @@
 use derive_more::{Display, From, Into};
 use serde::{Deserialize, Serialize};
 
+pub mod id_set;
 pub mod incoming;
 pub mod stored;
 
@@ -1,58 +1,190 @@
 use std::fmt::Debug;
 
+use derive_more::Display;
 use kv::Raw;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-use super::incoming::IncomingTx;
+use crate::{account::AccountId, Money};
+
+use super::{
+    incoming::{IncomingTx, IncomingTxDetails},
+    TxId,
+};
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
-#[repr(C)]
 pub struct TxDetails {
     pub original_tx: IncomingTx,
     pub state: TxState,
+    /// Number of times the transaction has been disputed
+    pub disputes: u8,
+    /// Bank's clock when the transaction was applied, see [`crate::bank::Bank::now`]
+    pub applied_at: u64,
 }
 
 impl TxDetails {
     pub fn with_state(self, state: TxState) -> Self {
-        Self {
-            original_tx: self.original_tx,
-            state,
+        Self { state, ..self }
+    }
+}
+
+/// Version of the on-disk encoding, bump whenever the layout below changes
+const ENCODING_VERSION: u8 = 2;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DecodeError {
+    #[error("unknown encoding version {0}")]
+    UnknownVersion(u8),
+    #[error("record is truncated")]
+    Truncated,
+    #[error("{0} trailing bytes after the record")]
+    TrailingBytes(usize),
+    #[error("unknown transaction type tag {0}")]
+    UnknownTxType(u8),
+    #[error("unknown transaction state tag {0}")]
+    UnknownTxState(u8),
+    #[error("invalid amount: {0}")]
+    Amount(#[from] rust_decimal::Error),
+}
+
+// Layout, all integers are little-endian:
+//   version: u8
+//   tx id: u32
+//   account id: u16
+//   tx type: u8
+//   for deposits and withdrawals only, amount mantissa: i128 and amount scale: u8
+//   tx state: u8
+//   disputes: u8
+//   since version 2, applied at: u64
+impl TxDetails {
+    pub fn encode(&self) -> Vec<u8> {
+        let tx = &self.original_tx;
+        let mut out = Vec::with_capacity(36);
+        out.push(ENCODING_VERSION);
+        out.extend_from_slice(&tx.id.0.to_le_bytes());
+        out.extend_from_slice(&tx.account.0.to_le_bytes());
+        let (tag, amount) = match tx.details {
+            IncomingTxDetails::Deposit(amount) => (0, Some(amount)),
+            IncomingTxDetails::Withdrawal(amount) => (1, Some(amount)),
+            IncomingTxDetails::Dispute => (2, None),
+            IncomingTxDetails::Resolve => (3, None),
+            IncomingTxDetails::Chargeback => (4, None),
+            IncomingTxDetails::Lock => (5, None),
+            IncomingTxDetails::Unlock => (6, None),
+        };
+        out.push(tag);
+        if let Some(amount) = amount {
+            out.extend_from_slice(&amount.mantissa().to_le_bytes());
+            // scale never exceeds 28
+            out.push(amount.scale() as u8);
         }
+        out.push(match self.state {
+            TxState::Complete => 0,
+            TxState::UnderDispute => 1,
+            TxState::Resolved => 2,
+            TxState::ChargedBack => 3,
+        });
+        out.push(self.disputes);
+        out.extend_from_slice(&self.applied_at.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader(bytes);
+        let version = reader.u8()?;
+        if !(1..=ENCODING_VERSION).contains(&version) {
+            return Err(DecodeError::UnknownVersion(version));
+        }
+        let id = TxId(u32::from_le_bytes(reader.array()?));
+        let account = AccountId(u16::from_le_bytes(reader.array()?));
+        let details = match reader.u8()? {
+            0 => IncomingTxDetails::Deposit(reader.amount()?),
+            1 => IncomingTxDetails::Withdrawal(reader.amount()?),
+            2 => IncomingTxDetails::Dispute,
+            3 => IncomingTxDetails::Resolve,
+            4 => IncomingTxDetails::Chargeback,
+            5 => IncomingTxDetails::Lock,
+            6 => IncomingTxDetails::Unlock,
+            tag => return Err(DecodeError::UnknownTxType(tag)),
+        };
+        let state = match reader.u8()? {
+            0 => TxState::Complete,
+            1 => TxState::UnderDispute,
+            2 => TxState::Resolved,
+            3 => TxState::ChargedBack,
+            tag => return Err(DecodeError::UnknownTxState(tag)),
+        };
+        let disputes = reader.u8()?;
+        // Transactions stored before the clock existed are the oldest there are
+        let applied_at = if version >= 2 {
+            u64::from_le_bytes(reader.array()?)
+        } else {
+            0
+        };
+        if !reader.0.is_empty() {
+            return Err(DecodeError::TrailingBytes(reader.0.len()));
+        }
+
+        Ok(Self {
+            original_tx: IncomingTx {
+                id,
+                account,
+                details,
+            },
+            state,
+            disputes,
+            applied_at,
+        })
     }
 }
-const TX_DETAILS_SIZE: usize = std::mem::size_of::<TxDetails>();
 
-impl From<Raw> for TxDetails {
-    fn from(raw: Raw) -> Self {
-        debug_assert_eq!(raw.len(), TX_DETAILS_SIZE);
+struct Reader<'b>(&'b [u8]);
+
+impl<'b> Reader<'b> {
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.0.len() < N {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.0.split_at(N);
+        self.0 = tail;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn amount(&mut self) -> Result<Money, DecodeError> {
+        let mantissa = i128::from_le_bytes(self.array()?);
+        let scale = self.u8()?;
+        Ok(Money::try_from_i128_with_scale(mantissa, scale.into())?)
+    }
+}
 
-        // MaybeUninit is still quirky with arrays on stable
-        // Also, zeroing out will be optimized away in --release
-        let mut bytes = [0; TX_DETAILS_SIZE];
-        bytes.copy_from_slice(&raw);
+impl TryFrom<Raw> for TxDetails {
+    type Error = DecodeError;
 
-        // SAFETY
-        // safe because TxDetails is Copy
-        unsafe { std::mem::transmute(bytes) }
+    fn try_from(raw: Raw) -> Result<Self, Self::Error> {
+        Self::decode(&raw)
     }
 }
 
 impl From<TxDetails> for Raw {
     fn from(tx: TxDetails) -> Self {
-        // SAFETY
-        // safe because TxDetails is Copy
-        let v: [u8; TX_DETAILS_SIZE] = unsafe { std::mem::transmute(tx) };
-        Raw::from(&v)
+        Raw::from(tx.encode())
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Copy, Clone, Debug, Display, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
-#[repr(C)]
 pub enum TxState {
+    #[display(fmt = "complete")]
     Complete,
+    #[display(fmt = "under-dispute")]
     UnderDispute,
+    #[display(fmt = "resolved")]
     Resolved,
+    #[display(fmt = "charged-back")]
     ChargedBack,
 }
 
@@ -63,19 +195,81 @@ mod tests {
 
     use crate::tx::incoming::IncomingTx;
 
-    use super::TxDetails;
+    use super::{DecodeError, TxDetails, TxState};
+
+    fn deposit() -> TxDetails {
+        TxDetails {
+            original_tx: IncomingTx::deposit(123, 456, "789.1112").unwrap(),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: 42,
+        }
+    }
 
     #[test]
     fn tx_details_to_raw_and_back() {
-        let original = TxDetails {
-            original_tx: IncomingTx::deposit(123, 456, "789.1112").unwrap(),
-            state: super::TxState::Complete,
-        };
+        let original = deposit();
 
-        let raw: Raw = original.clone().into();
+        let raw: Raw = original.into();
         assert_debug_snapshot!(&raw);
 
-        let recovered: TxDetails = raw.into();
+        let recovered: TxDetails = raw.try_into().unwrap();
         assert_eq!(recovered, original);
     }
+
+    #[test]
+    fn tx_details_without_amount_to_raw_and_back() {
+        let original = TxDetails {
+            original_tx: IncomingTx::lock(123, 456),
+            state: TxState::Complete,
+            disputes: 0,
+            applied_at: 0,
+        };
+
+        assert_eq!(TxDetails::decode(&original.encode()), Ok(original));
+    }
+
+    #[test]
+    fn decoding_rejects_unknown_version() {
+        let mut bytes = deposit().encode();
+        bytes[0] = 0;
+        assert_eq!(
+            TxDetails::decode(&bytes),
+            Err(DecodeError::UnknownVersion(0))
+        );
+    }
+
+    #[test]
+    fn decodes_version_1_records() {
+        let mut bytes = deposit().encode();
+        bytes[0] = 1;
+        bytes.truncate(bytes.len() - 8);
+        assert_eq!(
+            TxDetails::decode(&bytes),
+            Ok(TxDetails {
+                applied_at: 0,
+                ..deposit()
+            })
+        );
+    }
+
+    #[test]
+    fn decoding_rejects_truncated_record() {
+        let bytes = deposit().encode();
+        assert_eq!(
+            TxDetails::decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn decoding_rejects_unknown_state() {
+        let mut bytes = deposit().encode();
+        let state = bytes.len() - 10;
+        bytes[state] = 42;
+        assert_eq!(
+            TxDetails::decode(&bytes),
+            Err(DecodeError::UnknownTxState(42))
+        );
+    }
 }
@@ -1,13 +1,25 @@
-use std::io::{Read, Write};
+use std::{
+    io::{Read, Write},
+    path::Path,
+};
 
 use kv::{Config, Integer, Raw, Store};
+use serde::Serialize;
 use tempdir::TempDir;
 use thiserror::Error;
 
 use crate::{
-    account::AccountState,
-    bank::{Bank, InMemoryTxCache, OnDiskTxCache, TxCache},
-    io::{csv_reader, ParseError},
+    account::{Account, AccountId, AccountState},
+    bank::{
+        AccountStore, ApplyTxError, Bank, CacheError, InMemoryTxCache, OnDiskAccountStore,
+        OnDiskTxCache, TxCache,
+    },
+    io::{decompressed, InputFormat, ParseError, TxReader, TxSource},
+    ledger::JournalEntry,
+    report::{
+        AuditSink, JournalSink, LockChange, QuarantineSink, RejectedTx, RejectionSink, ReportError,
+    },
+    Money,
 };
 
 #[derive(Error, Debug)]
@@ -17,53 +29,297 @@ pub enum HistoricRunError {
     #[error("I/O error: {0}")]
     IO(#[from] std::io::Error),
     #[error("Cache error: {0}")]
-    Cache(#[from] kv::Error),
+    Cache(#[from] CacheError),
+    #[error("error writing report: {0}")]
+    Report(#[from] ReportError),
 }
 
-pub fn historic_run(input: impl Read, cache: Box<dyn TxCache>) -> Result<Bank, HistoricRunError> {
-    let mut state = Bank::with_cache(cache);
+#[derive(Error, Debug)]
+pub enum WriteStateError {
+    #[error("error writing CSV: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("error writing JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("I/O error: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("Cache error: {0}")]
+    Cache(#[from] CacheError),
+}
 
-    for tx in csv_reader(input) {
-        let tx = tx?;
-        state.apply_tx(tx);
-    }
+/// What to do with a record that can't be parsed into a transaction
+#[derive(Default)]
+pub enum OnParseError<'a> {
+    /// Stop the run and return the error
+    #[default]
+    Abort,
+    /// Count the record and carry on
+    Skip,
+    /// Count the record, write it to the quarantine sink and carry on
+    Quarantine(&'a mut dyn QuarantineSink),
+}
+
+/// Called with the source of a skipped record and the reason it was skipped
+pub type SkipCallback<'a> = dyn FnMut(&str, &ParseError) + 'a;
+
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// Format [`historic_run_with`] reads the input in
+    pub input_format: InputFormat,
+    pub on_parse_error: OnParseError<'a>,
+    /// Called with the source and error of every record skipped or quarantined
+    pub on_skipped: Option<&'a mut SkipCallback<'a>>,
+    pub rejected: Option<&'a mut dyn RejectionSink>,
+    /// Receives every change of an account's locked state, by chargebacks and administrative locks alike
+    pub audit: Option<&'a mut dyn AuditSink>,
+    /// Receives a balanced journal entry for every movement of money
+    pub journal: Option<&'a mut dyn JournalSink>,
+}
 
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RunSummary {
+    /// Number of records skipped because they couldn't be parsed
+    pub skipped: u64,
+}
+
+pub fn historic_run<C: TxCache>(input: impl Read, cache: C) -> Result<Bank<C>, HistoricRunError> {
+    let (state, _) = historic_run_with(input, Bank::with_cache(cache), RunOptions::default())?;
     Ok(state)
 }
 
+pub fn historic_run_with<C: TxCache, A: AccountStore>(
+    input: impl Read,
+    state: Bank<C, A>,
+    options: RunOptions,
+) -> Result<(Bank<C, A>, RunSummary), HistoricRunError> {
+    let records = TxReader::new(options.input_format, decompressed(input)?);
+    historic_run_from(records, state, options)
+}
+
+/// Same as [`historic_run_with`], but over transactions read already, e.g. from several inputs by
+/// [`crate::io::MultiReader`], `options.input_format` is ignored
+pub fn historic_run_from<C: TxCache, A: AccountStore>(
+    mut records: impl TxSource,
+    mut state: Bank<C, A>,
+    mut options: RunOptions,
+) -> Result<(Bank<C, A>, RunSummary), HistoricRunError> {
+    let mut summary = RunSummary::default();
+
+    while let Some(tx) = records.next() {
+        let tx = match tx {
+            Ok(tx) => tx,
+            Err(e) if !e.is_recoverable() => return Err(e.into()),
+            Err(e) => {
+                match &mut options.on_parse_error {
+                    OnParseError::Abort => return Err(e.into()),
+                    OnParseError::Skip => {}
+                    OnParseError::Quarantine(quarantine) => quarantine.quarantine(
+                        records.source(),
+                        records.line(),
+                        records.header(),
+                        &e.raw,
+                    )?,
+                }
+                if let Some(on_skipped) = options.on_skipped.as_mut() {
+                    on_skipped(records.source(), &e);
+                }
+                summary.skipped += 1;
+                continue;
+            }
+        };
+        if let Some(timestamp) = records.timestamp() {
+            state.advance_clock(timestamp);
+        }
+        let was_locked = is_locked(&state, tx.account)?;
+        match state.apply_tx(tx) {
+            Ok(applied) => {
+                if let Some(journal) = options.journal.as_mut() {
+                    if let Some(entry) =
+                        JournalEntry::for_tx(records.source(), records.line(), &tx, &applied)
+                    {
+                        journal.journal(entry)?;
+                    }
+                }
+                let locked = is_locked(&state, tx.account)?;
+                if locked != was_locked {
+                    if let Some(audit) = options.audit.as_mut() {
+                        let change = LockChange::new(records.source(), records.line(), &tx, locked)
+                            .with_operator(records.operator())
+                            .with_timestamp(records.timestamp());
+                        audit.lock_changed(change)?;
+                    }
+                }
+            }
+            Err(ApplyTxError::Cache(e)) => return Err(e.into()),
+            Err(ApplyTxError::Rejected(reason)) => {
+                if let Some(rejected) = options.rejected.as_mut() {
+                    rejected.rejected(RejectedTx::new(
+                        records.source(),
+                        records.line(),
+                        &tx,
+                        reason,
+                    ))?;
+                }
+            }
+        }
+    }
+
+    state.flush()?;
+
+    Ok((state, summary))
+}
+
+fn is_locked<C: TxCache, A: AccountStore>(
+    state: &Bank<C, A>,
+    account: AccountId,
+) -> Result<bool, CacheError> {
+    Ok(state
+        .account(account)?
+        .map(|account| account.state == AccountState::Frozen)
+        .unwrap_or(false))
+}
+
 pub fn historic_run_small(input: impl Read) -> Result<Bank, HistoricRunError> {
-    historic_run(input, Box::new(InMemoryTxCache::default()))
+    historic_run(input, InMemoryTxCache::default())
 }
 
-pub fn historic_run_large(input: impl Read) -> Result<(Bank, TempDir), HistoricRunError> {
+pub fn historic_run_large(
+    input: impl Read,
+) -> Result<(Bank<OnDiskTxCache<'static>>, TempDir), HistoricRunError> {
     let temp_dir = TempDir::new("nesse-bank")?;
-    let store_cfg = Config::new(temp_dir.path());
+
+    Ok((historic_run(input, disk_cache(temp_dir.path())?)?, temp_dir))
+}
+
+/// Opens the accounts stored in `path`, creating an empty store if there's none
+pub fn disk_account_store(path: &Path) -> Result<OnDiskAccountStore<'static>, CacheError> {
+    let store = Store::new(Config::new(path))?;
+    Ok(OnDiskAccountStore::new(
+        store.bucket::<Integer, Raw>(Some("accounts"))?,
+    ))
+}
+
+/// Opens the disk cache stored in `path`, creating an empty one if there's none
+pub fn disk_cache(path: &Path) -> Result<OnDiskTxCache<'static>, CacheError> {
+    let store_cfg = Config::new(path);
     let store = Store::new(store_cfg)?;
     let tx_bucket = store.bucket::<Integer, Raw>(Some("tx"))?;
+    Ok(OnDiskTxCache::new(tx_bucket))
+}
 
-    Ok((
-        historic_run(input, Box::new(OnDiskTxCache::new(tx_bucket)))?,
-        temp_dir,
-    ))
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single array of accounts
+    Json,
+    JsonLines,
+    /// Columns aligned for reading in a terminal, the whole state is kept in memory to measure them
+    Table,
+}
+
+/// A single row of the final state of accounts
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct AccountRow {
+    pub client: AccountId,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
 }
 
-pub fn write_state(state: Bank, output: impl Write) -> Result<(), csv::Error> {
-    let mut out = csv::WriterBuilder::new().from_writer(output);
-    out.write_record(&["client", "available", "held", "total", "locked"])?;
-
-    for (account_id, account) in state.into_accounts() {
-        out.write_record(&[
-            account_id.to_string().as_str(),
-            account.balance.to_string().as_str(),
-            account.held.to_string().as_str(),
-            (account.balance + account.held).to_string().as_str(),
-            if account.state == AccountState::Frozen {
-                "true"
-            } else {
-                "false"
-            },
-        ])?;
+impl AccountRow {
+    const HEADER: [&'static str; 5] = ["client", "available", "held", "total", "locked"];
+
+    pub fn new(client: AccountId, account: &Account) -> Self {
+        Self {
+            client,
+            available: account.balance,
+            held: account.held,
+            total: account.balance + account.held,
+            locked: account.state == AccountState::Frozen,
+        }
+    }
+
+    fn fields(&self) -> [String; 5] {
+        [
+            self.client.to_string(),
+            self.available.to_string(),
+            self.held.to_string(),
+            self.total.to_string(),
+            self.locked.to_string(),
+        ]
+    }
+}
+
+pub fn write_state<C: TxCache, A: AccountStore>(
+    state: Bank<C, A>,
+    output: impl Write,
+) -> Result<(), WriteStateError> {
+    write_state_as(state, OutputFormat::Csv, output)
+}
+
+pub fn write_state_as<C: TxCache, A: AccountStore>(
+    mut state: Bank<C, A>,
+    format: OutputFormat,
+    mut output: impl Write,
+) -> Result<(), WriteStateError> {
+    let rows = state
+        .accounts()?
+        .map(|account| account.map(|(id, account)| AccountRow::new(id, &account)));
+
+    match format {
+        OutputFormat::Csv => {
+            let mut out = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(&mut output);
+            out.write_record(AccountRow::HEADER)?;
+            for row in rows {
+                out.serialize(row?)?;
+            }
+            out.flush()?;
+        }
+        OutputFormat::Json => {
+            output.write_all(b"[")?;
+            for (i, row) in rows.enumerate() {
+                if i > 0 {
+                    output.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut output, &row?)?;
+            }
+            output.write_all(b"]\n")?;
+        }
+        OutputFormat::JsonLines => {
+            for row in rows {
+                serde_json::to_writer(&mut output, &row?)?;
+                output.write_all(b"\n")?;
+            }
+        }
+        OutputFormat::Table => {
+            let rows = rows
+                .map(|row| row.map(|row| row.fields()))
+                .collect::<Result<Vec<_>, _>>()?;
+            let mut widths = AccountRow::HEADER.map(str::len);
+            for fields in &rows {
+                for (width, field) in widths.iter_mut().zip(fields) {
+                    *width = (*width).max(field.len());
+                }
+            }
+
+            let header = AccountRow::HEADER.map(str::to_owned);
+            for fields in std::iter::once(&header).chain(&rows) {
+                // Numbers are right-aligned, `locked` is the last column and needs no padding
+                for (i, (field, width)) in fields.iter().zip(widths).enumerate() {
+                    match i {
+                        0 => write!(output, "{:>1$}", field, width)?,
+                        4 => write!(output, "  {}", field)?,
+                        _ => write!(output, "  {:>1$}", field, width)?,
+                    }
+                }
+                output.write_all(b"\n")?;
+            }
+        }
     }
+    output.flush()?;
 
     Ok(())
 }